@@ -1,10 +1,197 @@
-#[derive(PartialEq, Eq, Debug, Clone)]
+use crate::lexer::Span;
+
+#[derive(PartialEq, Debug, Clone)]
 pub enum Node {
     Identifier(String),
     List(Vec<Node>),
     StringLiteral(String),
     IntegerLiteral(i32),
+    FloatLiteral(f64),
+    /// Only produced by reader macros; the default syntax has no boolean
+    /// literals.
+    BoolLiteral(bool),
     Quote(Box<Node>),
+    /// The text of a `;` comment, without the `;`. Only produced by a parser
+    /// that keeps comments; evaluates to nothing.
+    Comment(String),
+}
+
+/// A node together with the stretch of source it was parsed from. Equality
+/// only looks at the nodes, so the same form parsed from two places compares
+/// equal; `eq_with_spans` also compares the spans.
+#[derive(Debug, Clone)]
+pub struct Spanned {
+    pub node: Node,
+    pub span: Span,
+}
+
+impl Spanned {
+    pub fn eq_with_spans(&self, other: &Self) -> bool {
+        self.node == other.node && self.span == other.span
+    }
+}
+
+impl PartialEq for Spanned {
+    fn eq(&self, other: &Self) -> bool {
+        self.node == other.node
+    }
+}
+
+impl Node {
+    pub fn list(nodes: Vec<Node>) -> Self {
+        Self::List(nodes)
+    }
+
+    pub fn int(i: i32) -> Self {
+        Self::IntegerLiteral(i)
+    }
+
+    pub fn sym(name: &str) -> Self {
+        Self::Identifier(name.into())
+    }
+
+    pub fn float(f: f64) -> Self {
+        Self::FloatLiteral(f)
+    }
+
+    pub fn string(s: &str) -> Self {
+        Self::StringLiteral(s.into())
+    }
+
+    pub fn quote(node: Node) -> Self {
+        Self::Quote(Box::new(node))
+    }
+
+    /// The nodes directly inside this one: the elements of a list or the
+    /// quoted node. Atoms have none.
+    pub fn children(&self) -> impl Iterator<Item = &Node> {
+        let children: &[Node] = match self {
+            Node::List(nodes) => nodes,
+            Node::Quote(node) => std::slice::from_ref(node.as_ref()),
+            _ => &[],
+        };
+        children.iter()
+    }
+
+    /// Drops every comment inside this node, however deeply nested, so that
+    /// lists only hold the forms that are evaluated.
+    pub fn strip_comments(&mut self) {
+        match self {
+            Node::List(nodes) => {
+                nodes.retain(|node| !matches!(node, Node::Comment(_)));
+                nodes.iter_mut().for_each(Node::strip_comments);
+            }
+            Node::Quote(node) => node.strip_comments(),
+            _ => {}
+        }
+    }
+
+    /// The number of nodes in the tree rooted here, this one included.
+    pub fn node_count(&self) -> usize {
+        1 + self.children().map(Node::node_count).sum::<usize>()
+    }
+
+    /// Evaluates integer arithmetic and comparisons on literals ahead of
+    /// time, so `(+ 1 (* 2 3))` becomes `7`. This assumes the operators keep
+    /// their builtin meaning. Calls that would fail, by overflowing or
+    /// dividing by zero, are left for the runtime to report, and quoted code
+    /// is left alone. Macros see the folded arguments.
+    pub fn constant_fold(&self) -> Node {
+        let Node::List(nodes) = self else {
+            return self.clone();
+        };
+        match nodes.first() {
+            Some(Node::Identifier(op))
+                if ["quote", "syntax-quote", "comment", "defmacro"].contains(&op.as_str()) =>
+            {
+                return self.clone();
+            }
+            _ => {}
+        }
+        let folded: Vec<Node> = nodes.iter().map(Node::constant_fold).collect();
+        match folded.as_slice() {
+            [Node::Identifier(op), Node::IntegerLiteral(a), Node::IntegerLiteral(b)] => {
+                fold_int_op(op, *a, *b).unwrap_or(Node::List(folded))
+            }
+            _ => Node::List(folded),
+        }
+    }
+
+    /// Renders the node back into source text that parses to the same node.
+    /// The exceptions are nodes the parser can't produce: booleans, strings
+    /// containing `"` and floats that are infinite or NaN, which have no
+    /// literal syntax.
+    pub fn to_source(&self) -> String {
+        match self {
+            Node::Identifier(id) => id.clone(),
+            Node::List(nodes) => {
+                let inner: Vec<_> = nodes.iter().map(Node::to_source).collect();
+                format!("({})", inner.join(" "))
+            }
+            Node::StringLiteral(s) => format!("\"{s}\""),
+            Node::IntegerLiteral(i) => i.to_string(),
+            // Display never uses an exponent, which the parser doesn't read,
+            // but it drops the fraction of whole numbers, which it needs.
+            Node::FloatLiteral(f) if f.is_finite() && f.fract() == 0.0 => format!("{f}.0"),
+            Node::FloatLiteral(f) => f.to_string(),
+            // Reads back as the identifier bound to the same value.
+            Node::BoolLiteral(b) => b.to_string(),
+            Node::Quote(node) => format!("'{}", node.to_source()),
+            Node::Comment(text) => format!(";{text}\n"),
+        }
+    }
+}
+
+fn fold_int_op(op: &str, a: i32, b: i32) -> Option<Node> {
+    let int = |i: Option<i32>| i.map(Node::IntegerLiteral);
+    match op {
+        "+" => int(a.checked_add(b)),
+        "-" => int(a.checked_sub(b)),
+        "*" => int(a.checked_mul(b)),
+        "/" => int(a.checked_div(b)),
+        "mod" => int(a.checked_rem(b)),
+        "=" => Some(Node::BoolLiteral(a == b)),
+        "!=" => Some(Node::BoolLiteral(a != b)),
+        "<" => Some(Node::BoolLiteral(a < b)),
+        ">" => Some(Node::BoolLiteral(a > b)),
+        "<=" => Some(Node::BoolLiteral(a <= b)),
+        ">=" => Some(Node::BoolLiteral(a >= b)),
+        _ => None,
+    }
+}
+
+impl From<i32> for Node {
+    fn from(value: i32) -> Self {
+        Self::int(value)
+    }
+}
+
+impl From<f64> for Node {
+    fn from(value: f64) -> Self {
+        Self::float(value)
+    }
+}
+
+impl From<&str> for Node {
+    fn from(value: &str) -> Self {
+        Self::string(value)
+    }
+}
+
+/// Builds a [`Node`] from Lisp-like tokens: parenthesized groups become
+/// lists, integer and string literals become literals and anything else
+/// becomes an identifier, e.g. `node!((+ 1 (* 2 x)))`.
+#[macro_export]
+macro_rules! node {
+    (($($inner:tt)*)) => {
+        $crate::ast::Node::list(vec![$($crate::node!($inner)),*])
+    };
+    ($lit:literal) => {
+        $crate::ast::Node::from($lit)
+    };
+    ($sym:tt) => {
+        $crate::ast::Node::sym(stringify!($sym))
+    };
 }
 
 // impl Node {
@@ -24,3 +211,133 @@ pub enum Node {
 //         matches!(self, Self::IntegerLiteral(_))
 //     }
 // }
+
+#[cfg(test)]
+mod test {
+    use super::{Node, Spanned};
+    use crate::runtime::{Data, Runtime};
+
+    #[test]
+    fn test_builders() {
+        let built = Node::list(vec![Node::sym("+"), Node::int(1), Node::int(2)]);
+        assert_eq!(built, node!((+ 1 2)));
+        assert_eq!(
+            Node::list(vec![
+                Node::sym("debug"),
+                Node::string("s"),
+                Node::list(vec![Node::sym("x")]),
+            ]),
+            node!((debug "s" (x)))
+        );
+        let mut runtime = Runtime::try_new().unwrap();
+        assert_eq!(Data::Int(3), runtime.eval(built).unwrap());
+    }
+
+    #[test]
+    fn test_to_source() {
+        let src = "(print 1 \"a b\" '(x 'y))";
+        let (_, node) = crate::parser::node(src.as_bytes()).unwrap();
+        assert_eq!(src, node.to_source());
+    }
+
+    // Builds a random node of the kinds the parser produces, from the state
+    // of a xorshift generator.
+    fn random_node(state: &mut u64, depth: usize) -> Node {
+        let mut next = || {
+            *state ^= *state << 13;
+            *state ^= *state >> 7;
+            *state ^= *state << 17;
+            *state
+        };
+        let names = ["x", "-", "->>", "+", "list-of-2", "*x*"];
+        let strings = ["", "a b", "\\n", "-1"];
+        match next() % if depth == 0 { 4 } else { 6 } {
+            0 => Node::sym(names[next() as usize % names.len()]),
+            1 => Node::int(next() as i32),
+            2 => loop {
+                let f = f64::from_bits(next());
+                if f.is_finite() {
+                    break Node::float(f);
+                }
+            },
+            3 => Node::string(strings[next() as usize % strings.len()]),
+            4 => Node::quote(random_node(state, depth - 1)),
+            _ => {
+                let len = 1 + next() as usize % 3;
+                Node::list((0..len).map(|_| random_node(state, depth - 1)).collect())
+            }
+        }
+    }
+
+    #[test]
+    fn test_to_source_round_trips() {
+        let fixed = [
+            Node::int(-5),
+            Node::int(i32::MIN),
+            Node::float(1e20),
+            Node::float(-2.5e-8),
+            Node::float(-0.0),
+            Node::float(f64::MAX),
+        ];
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        let random = (0..2000).map(|_| random_node(&mut state, 3));
+        for node in fixed.into_iter().chain(random) {
+            let src = node.to_source();
+            let parsed = crate::parser::program(src.as_bytes()).unwrap();
+            assert_eq!(vec![node], parsed, "{src}");
+        }
+    }
+
+    #[test]
+    fn test_children() {
+        let list = node!((a b c));
+        let names: Vec<_> = list.children().map(Node::to_source).collect();
+        assert_eq!(vec!["a", "b", "c"], names);
+        let quoted = Node::quote(Node::sym("x"));
+        assert_eq!(vec![&Node::sym("x")], quoted.children().collect::<Vec<_>>());
+        assert_eq!(0, Node::int(1).children().count());
+    }
+
+    #[test]
+    fn test_node_count() {
+        assert_eq!(7, node!((+ 1 (* 2 3))).node_count());
+        assert_eq!(2, Node::quote(Node::sym("x")).node_count());
+        assert_eq!(1, Node::int(1).node_count());
+    }
+
+    #[test]
+    fn test_spanned_eq() {
+        let (nodes, _) = crate::parser::program_recovering(b"(f 1) (f 1) (f 2)");
+        assert_eq!(nodes[0], nodes[1]);
+        assert!(!nodes[0].eq_with_spans(&nodes[1]));
+        assert!(nodes[0].eq_with_spans(&nodes[0].clone()));
+        assert_ne!(nodes[1], nodes[2]);
+        let moved = Spanned {
+            span: 100..105,
+            ..nodes[0].clone()
+        };
+        assert_eq!(nodes[0], moved);
+    }
+
+    #[test]
+    fn test_constant_fold() {
+        assert_eq!(Node::int(7), node!((+ 1 (* 2 3))).constant_fold());
+        assert_eq!(node!((+ x 1)), node!((+ x 1)).constant_fold());
+        assert_eq!(node!((+ x 6)), node!((+ x (* 2 3))).constant_fold());
+        assert_eq!(
+            Node::list(vec![Node::sym("if"), Node::BoolLiteral(true), Node::int(1)]),
+            node!((if (< 1 2) 1)).constant_fold()
+        );
+        // Failures are kept for the runtime to report.
+        assert_eq!(node!((/ 1 0)), node!((/ 1 0)).constant_fold());
+        assert_eq!(
+            node!((quote (+ 1 2))),
+            node!((quote (+ 1 2))).constant_fold()
+        );
+
+        let mut runtime = Runtime::try_new().unwrap();
+        let program = node!((* (+ 2 3) (mod 17 (/ 10 2))));
+        assert_eq!(Node::int(10), program.constant_fold());
+        assert_eq!(Data::Int(10), runtime.eval(program).unwrap());
+    }
+}
@@ -0,0 +1,155 @@
+use crate::{ast::Node, parser};
+use nom::{
+    branch::alt,
+    bytes::streaming::{tag, take_while, take_while1},
+    character::{is_alphabetic, is_digit, is_newline, is_space},
+    combinator::{map, recognize},
+    multi::{many0_count, many1_count},
+    sequence::pair,
+    IResult,
+};
+use std::ops::Range;
+
+pub type Span = Range<usize>;
+
+#[derive(PartialEq, Debug, Clone)]
+pub enum TokenKind {
+    LParen,
+    RParen,
+    Quote,
+    Ident(String),
+    Int(i32),
+    Float(f64),
+    Str(String),
+    Whitespace,
+    Comment(String),
+}
+
+#[derive(PartialEq, Debug, Clone)]
+pub struct Token {
+    pub kind: TokenKind,
+    /// Byte offsets into the tokenized input.
+    pub span: Span,
+}
+
+pub(crate) fn is_separator(c: u8) -> bool {
+    is_space(c) || is_newline(c)
+}
+
+pub(crate) fn is_identifier_start(c: u8) -> bool {
+    is_alphabetic(c)
+        || c == b'+'
+        || c == b'-'
+        || c == b'*'
+        || c == b'/'
+        || c == b'_'
+        || c == b'='
+        || c == b'>'
+        || c == b'<'
+        || c == b'!'
+        || c == b'?'
+}
+
+pub(crate) fn is_identifier_body(c: u8) -> bool {
+    is_identifier_start(c) || is_digit(c)
+}
+
+pub fn whitespace(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    take_while1(is_separator)(input)
+}
+
+/// A `;` comment running to the end of the line, without the newline.
+pub fn comment(input: &[u8]) -> IResult<&[u8], &[u8]> {
+    recognize(pair(tag(";"), take_while(|c| c != b'\n')))(input)
+}
+
+/// Any run of whitespace and comments, possibly empty.
+pub fn separator0(input: &[u8]) -> IResult<&[u8], usize> {
+    many0_count(alt((whitespace, comment)))(input)
+}
+
+/// A non-empty run of whitespace and comments.
+pub fn separator1(input: &[u8]) -> IResult<&[u8], usize> {
+    many1_count(alt((whitespace, comment)))(input)
+}
+
+fn token_kind(input: &[u8]) -> IResult<&[u8], TokenKind> {
+    alt((
+        map(tag("("), |_| TokenKind::LParen),
+        map(tag(")"), |_| TokenKind::RParen),
+        map(tag("'"), |_| TokenKind::Quote),
+        map(whitespace, |_| TokenKind::Whitespace),
+        map(comment, |c| {
+            TokenKind::Comment(String::from_utf8_lossy(&c[1..]).into_owned())
+        }),
+        map(
+            alt((
+                parser::string_literal,
+                parser::float_literal,
+                parser::integer_literal,
+                parser::identifier,
+            )),
+            |atom| match atom {
+                Node::Identifier(id) => TokenKind::Ident(id),
+                Node::StringLiteral(s) => TokenKind::Str(s),
+                Node::FloatLiteral(f) => TokenKind::Float(f),
+                Node::IntegerLiteral(i) => TokenKind::Int(i),
+                _ => unreachable!(),
+            },
+        ),
+    ))(input)
+}
+
+/// Splits the whole input into tokens, keeping whitespace and comments.
+/// Like the parser this is streaming: a token that runs into the end of the
+/// input reports `Incomplete`.
+pub fn tokenize(input: &[u8]) -> IResult<&[u8], Vec<Token>> {
+    let mut tokens = Vec::new();
+    let mut rest = input;
+    while !rest.is_empty() {
+        let start = input.len() - rest.len();
+        let (next, kind) = token_kind(rest)?;
+        rest = next;
+        tokens.push(Token {
+            kind,
+            span: start..input.len() - rest.len(),
+        });
+    }
+    Ok((rest, tokens))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{tokenize, Token, TokenKind};
+
+    #[test]
+    fn test_tokenize() {
+        let (rest, tokens) = tokenize(b"(+ 1 \"x\")").unwrap();
+        assert!(rest.is_empty());
+        let expected = [
+            (TokenKind::LParen, 0..1),
+            (TokenKind::Ident("+".into()), 1..2),
+            (TokenKind::Whitespace, 2..3),
+            (TokenKind::Int(1), 3..4),
+            (TokenKind::Whitespace, 4..5),
+            (TokenKind::Str("x".into()), 5..8),
+            (TokenKind::RParen, 8..9),
+        ]
+        .map(|(kind, span)| Token { kind, span });
+        assert_eq!(expected.to_vec(), tokens);
+
+        let (_, tokens) = tokenize(b"'a ; hi\n)").unwrap();
+        let kinds: Vec<_> = tokens.into_iter().map(|t| t.kind).collect();
+        assert_eq!(
+            vec![
+                TokenKind::Quote,
+                TokenKind::Ident("a".into()),
+                TokenKind::Whitespace,
+                TokenKind::Comment(" hi".into()),
+                TokenKind::Whitespace,
+                TokenKind::RParen,
+            ],
+            kinds
+        );
+    }
+}
@@ -0,0 +1,530 @@
+use crate::repl::{LineReader, StdinReader};
+use std::io::{stdin, stdout, Read, Write};
+
+/// Reads lines from a terminal with basic editing: the arrow keys move the
+/// cursor and recall history, Home/End (or Ctrl-A/Ctrl-E) jump to the ends
+/// of the line, Ctrl-C discards the line and Ctrl-D on an empty line ends
+/// input. History is kept and persisted by the wrapped [`StdinReader`].
+pub struct TtyReader {
+    inner: StdinReader,
+    /// The terminal's settings, read once when the session starts. `None`
+    /// if they couldn't be read, in which case lines are read as-is.
+    terminal: Option<termios::Terminal>,
+}
+
+impl TtyReader {
+    pub fn new() -> Self {
+        Self {
+            inner: StdinReader::new(),
+            terminal: termios::Terminal::open(),
+        }
+    }
+}
+
+impl LineReader for TtyReader {
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        // Raw mode is only on while a line is being edited, so Ctrl-C still
+        // interrupts the program and scripts reading stdin see plain lines.
+        let Some(_raw) = self.terminal.as_ref().and_then(termios::Terminal::raw) else {
+            return self.inner.read_line(prompt);
+        };
+        let mut editor = LineEditor::new(self.inner.history());
+        let mut out = stdout();
+        let mut bytes = stdin().lock().bytes().map_while(|b| b.ok());
+        let columns = || termios::columns().unwrap_or(80);
+        editor.redraw(prompt, columns(), &mut out);
+        loop {
+            let key = read_key(&mut bytes)?;
+            match editor.handle(key) {
+                Edit::Continue => editor.redraw(prompt, columns(), &mut out),
+                Edit::Submit => {
+                    let _ = writeln!(out);
+                    return Some(editor.line());
+                }
+                Edit::Eof => {
+                    let _ = writeln!(out);
+                    return None;
+                }
+            }
+        }
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        self.inner.add_history(entry)
+    }
+
+    fn history(&self) -> &[String] {
+        self.inner.history()
+    }
+}
+
+// The standard library has no terminal control and no crate for it is
+// available to the build, so the few libc calls needed are declared here.
+// Only layouts known to be right are spelled out; elsewhere `open` gives up
+// and lines are read without editing.
+#[cfg(any(
+    target_os = "macos",
+    all(
+        target_os = "linux",
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )
+    )
+))]
+mod termios {
+    use std::os::raw::{c_int, c_ulong};
+
+    #[cfg(target_os = "linux")]
+    mod sys {
+        pub type Flag = u32;
+        pub const ISIG: Flag = 0o1;
+        pub const ICANON: Flag = 0o2;
+        pub const ECHO: Flag = 0o10;
+        pub const IEXTEN: Flag = 0o100000;
+        pub const VTIME: usize = 5;
+        pub const VMIN: usize = 6;
+        pub const TIOCGWINSZ: std::os::raw::c_ulong = 0x5413;
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        pub struct Termios {
+            pub iflag: Flag,
+            pub oflag: Flag,
+            pub cflag: Flag,
+            pub lflag: Flag,
+            pub line: u8,
+            pub cc: [u8; 32],
+            pub ispeed: u32,
+            pub ospeed: u32,
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    mod sys {
+        pub type Flag = std::os::raw::c_ulong;
+        pub const ECHO: Flag = 0x8;
+        pub const ISIG: Flag = 0x80;
+        pub const ICANON: Flag = 0x100;
+        pub const IEXTEN: Flag = 0x400;
+        pub const VMIN: usize = 16;
+        pub const VTIME: usize = 17;
+        pub const TIOCGWINSZ: std::os::raw::c_ulong = 0x4008_7468;
+
+        #[repr(C)]
+        #[derive(Clone, Copy, Default)]
+        pub struct Termios {
+            pub iflag: Flag,
+            pub oflag: Flag,
+            pub cflag: Flag,
+            pub lflag: Flag,
+            pub cc: [u8; 20],
+            pub ispeed: Flag,
+            pub ospeed: Flag,
+        }
+    }
+
+    use sys::*;
+
+    #[repr(C)]
+    #[derive(Default)]
+    struct WinSize {
+        rows: u16,
+        columns: u16,
+        x_pixels: u16,
+        y_pixels: u16,
+    }
+
+    const STDIN: c_int = 0;
+    const TCSANOW: c_int = 0;
+
+    extern "C" {
+        fn tcgetattr(fd: c_int, termios: *mut Termios) -> c_int;
+        fn tcsetattr(fd: c_int, action: c_int, termios: *const Termios) -> c_int;
+        fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+    }
+
+    pub struct Terminal {
+        saved: Termios,
+        raw: Termios,
+    }
+
+    impl Terminal {
+        /// Reads stdin's terminal settings; `None` if stdin isn't a terminal.
+        pub fn open() -> Option<Self> {
+            let mut saved = Termios::default();
+            // SAFETY: tcgetattr only writes a Termios, which saved is.
+            if unsafe { tcgetattr(STDIN, &mut saved) } != 0 {
+                return None;
+            }
+            let mut raw = saved;
+            raw.lflag &= !(ICANON | ECHO | ISIG | IEXTEN);
+            raw.cc[VMIN] = 1;
+            raw.cc[VTIME] = 0;
+            Some(Self { saved, raw })
+        }
+
+        /// Turns off echo and line buffering until the guard is dropped.
+        pub fn raw(&self) -> Option<RawMode<'_>> {
+            set(&self.raw)?;
+            Some(RawMode(self))
+        }
+    }
+
+    pub struct RawMode<'a>(&'a Terminal);
+
+    impl Drop for RawMode<'_> {
+        fn drop(&mut self) {
+            set(&self.0.saved);
+        }
+    }
+
+    fn set(termios: &Termios) -> Option<()> {
+        // SAFETY: tcsetattr only reads the Termios it is given.
+        (unsafe { tcsetattr(STDIN, TCSANOW, termios) } == 0).then_some(())
+    }
+
+    /// The terminal's current width, asked for on every redraw so that
+    /// resizing is picked up.
+    pub fn columns() -> Option<usize> {
+        let mut size = WinSize::default();
+        // SAFETY: TIOCGWINSZ only writes a WinSize, which size is.
+        let ok = unsafe { ioctl(STDIN, TIOCGWINSZ, &mut size as *mut WinSize) } == 0;
+        (ok && size.columns > 0).then_some(size.columns.into())
+    }
+}
+
+#[cfg(not(any(
+    target_os = "macos",
+    all(
+        target_os = "linux",
+        any(
+            target_arch = "x86",
+            target_arch = "x86_64",
+            target_arch = "arm",
+            target_arch = "aarch64",
+            target_arch = "riscv64"
+        )
+    )
+)))]
+mod termios {
+    pub struct Terminal;
+
+    impl Terminal {
+        pub fn open() -> Option<Self> {
+            None
+        }
+
+        pub fn raw(&self) -> Option<()> {
+            None
+        }
+    }
+
+    pub fn columns() -> Option<usize> {
+        None
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Interrupt,
+    Eof,
+    /// Anything else, such as an unknown escape sequence; ignored.
+    Other,
+}
+
+/// Decodes one key press from raw terminal input. `None` means the input
+/// ended.
+pub fn read_key(bytes: &mut impl Iterator<Item = u8>) -> Option<Key> {
+    let byte = bytes.next()?;
+    Some(match byte {
+        b'\r' | b'\n' => Key::Enter,
+        0x7f | 0x08 => Key::Backspace,
+        0x01 => Key::Home,
+        0x05 => Key::End,
+        0x03 => Key::Interrupt,
+        0x04 => Key::Eof,
+        0x1b => match (bytes.next(), bytes.next()) {
+            (Some(b'[' | b'O'), Some(b'A')) => Key::Up,
+            (Some(b'[' | b'O'), Some(b'B')) => Key::Down,
+            (Some(b'[' | b'O'), Some(b'C')) => Key::Right,
+            (Some(b'[' | b'O'), Some(b'D')) => Key::Left,
+            (Some(b'[' | b'O'), Some(b'H')) => Key::Home,
+            (Some(b'[' | b'O'), Some(b'F')) => Key::End,
+            // ESC [ 3 ~ is Delete; the other ESC [ n ~ keys are ignored.
+            (Some(b'['), Some(n @ b'0'..=b'9')) => match (n, bytes.next()) {
+                (b'3', Some(b'~')) => Key::Delete,
+                (b'1' | b'7', Some(b'~')) => Key::Home,
+                (b'4' | b'8', Some(b'~')) => Key::End,
+                _ => Key::Other,
+            },
+            _ => Key::Other,
+        },
+        b if b < 0x20 => Key::Other,
+        b => {
+            // The leading byte says how many continuation bytes follow.
+            let len = match b {
+                0xc0..=0xdf => 2,
+                0xe0..=0xef => 3,
+                0xf0..=0xf7 => 4,
+                _ => 1,
+            };
+            let mut buf = vec![b];
+            buf.extend(bytes.take(len - 1));
+            match std::str::from_utf8(&buf) {
+                Ok(s) => s.chars().next().map_or(Key::Other, Key::Char),
+                Err(_) => Key::Other,
+            }
+        }
+    })
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Edit {
+    Continue,
+    Submit,
+    Eof,
+}
+
+/// The line being edited, independent of any terminal.
+pub struct LineEditor<'a> {
+    line: Vec<char>,
+    cursor: usize,
+    history: &'a [String],
+    /// Which history entry is shown; `history.len()` is the new line.
+    shown: usize,
+    /// The new line, kept while browsing history.
+    draft: Vec<char>,
+}
+
+impl<'a> LineEditor<'a> {
+    pub fn new(history: &'a [String]) -> Self {
+        Self {
+            line: Vec::new(),
+            cursor: 0,
+            history,
+            shown: history.len(),
+            draft: Vec::new(),
+        }
+    }
+
+    pub fn line(&self) -> String {
+        self.line.iter().collect()
+    }
+
+    pub fn handle(&mut self, key: Key) -> Edit {
+        match key {
+            Key::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            Key::Enter => return Edit::Submit,
+            Key::Eof if self.line.is_empty() => return Edit::Eof,
+            Key::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            Key::Delete | Key::Eof if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up if self.shown > 0 => self.show(self.shown - 1),
+            Key::Down if self.shown < self.history.len() => self.show(self.shown + 1),
+            Key::Interrupt => {
+                self.line.clear();
+                self.cursor = 0;
+                self.shown = self.history.len();
+            }
+            _ => {}
+        }
+        Edit::Continue
+    }
+
+    fn show(&mut self, index: usize) {
+        if self.shown == self.history.len() {
+            self.draft = std::mem::take(&mut self.line);
+        }
+        self.shown = index;
+        self.line = match self.history.get(index) {
+            // Multi-line entries are recalled on one line, which reads the
+            // same outside of string literals.
+            Some(entry) => entry.replace('\n', " ").chars().collect(),
+            None => self.draft.clone(),
+        };
+        self.cursor = self.line.len();
+    }
+
+    fn redraw(&self, prompt: &str, columns: usize, out: &mut impl Write) {
+        let room = columns.saturating_sub(width(prompt.chars()) + 1).max(1);
+        let (start, end) = self.visible(room);
+        let shown: String = self.line[start..end].iter().collect();
+        let _ = write!(out, "\r{prompt}{shown}\x1b[K");
+        let back = width(self.line[self.cursor..end].iter().copied());
+        if back > 0 {
+            let _ = write!(out, "\x1b[{back}D");
+        }
+        let _ = out.flush();
+    }
+
+    // The stretch of the line that fits in room columns with the cursor in
+    // view. Long lines scroll sideways rather than wrap, since a wrapped line
+    // would leave the redraw on the wrong row.
+    fn visible(&self, room: usize) -> (usize, usize) {
+        let mut start = 0;
+        while width(self.line[start..self.cursor].iter().copied()) > room {
+            start += 1;
+        }
+        let mut end = start;
+        let mut used = 0;
+        while let Some(&c) = self.line.get(end) {
+            if used + char_width(c) > room {
+                break;
+            }
+            used += char_width(c);
+            end += 1;
+        }
+        (start, end)
+    }
+}
+
+fn width(chars: impl Iterator<Item = char>) -> usize {
+    chars.map(char_width).sum()
+}
+
+/// How many terminal columns `c` takes up: none for combining marks and
+/// other zero-width characters, two for East Asian wide characters and
+/// emoji, and one for the rest.
+pub fn char_width(c: char) -> usize {
+    match c as u32 {
+        0x0300..=0x036f
+        | 0x1ab0..=0x1aff
+        | 0x1dc0..=0x1dff
+        | 0x200b..=0x200f
+        | 0x20d0..=0x20ff
+        | 0xfe00..=0xfe0f
+        | 0xfe20..=0xfe2f => 0,
+        0x1100..=0x115f
+        | 0x2e80..=0x303e
+        | 0x3041..=0x33ff
+        | 0x3400..=0x4dbf
+        | 0x4e00..=0x9fff
+        | 0xa000..=0xa4cf
+        | 0xac00..=0xd7a3
+        | 0xf900..=0xfaff
+        | 0xfe30..=0xfe4f
+        | 0xff00..=0xff60
+        | 0xffe0..=0xffe6
+        | 0x1f300..=0x1f64f
+        | 0x1f900..=0x1f9ff
+        | 0x20000..=0x3fffd => 2,
+        _ => 1,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{char_width, read_key, Edit, Key, LineEditor};
+
+    fn keys(input: &[u8]) -> Vec<Key> {
+        let mut bytes = input.iter().copied();
+        std::iter::from_fn(|| read_key(&mut bytes)).collect()
+    }
+
+    #[test]
+    fn test_read_key() {
+        assert_eq!(
+            vec![
+                Key::Char('a'),
+                Key::Up,
+                Key::Left,
+                Key::Delete,
+                Key::Char('é'),
+                Key::Backspace,
+                Key::Enter,
+            ],
+            keys("a\x1b[A\x1b[D\x1b[3~é\x7f\r".as_bytes())
+        );
+    }
+
+    #[test]
+    fn test_editing_and_history() {
+        let history = vec!["(+ 1 2)".to_string(), "(debug\n 1)".to_string()];
+        let mut editor = LineEditor::new(&history);
+        for key in keys(b"(lsst 1)\x1b[D\x1b[D\x1b[D\x1b[D\x1b[D\x7fi") {
+            assert_eq!(Edit::Continue, editor.handle(key));
+        }
+        assert_eq!("(list 1)", editor.line());
+        assert_eq!(3, editor.cursor);
+
+        editor.handle(Key::Up);
+        assert_eq!("(debug  1)", editor.line());
+        editor.handle(Key::Up);
+        editor.handle(Key::Up);
+        assert_eq!("(+ 1 2)", editor.line());
+        editor.handle(Key::Down);
+        editor.handle(Key::Down);
+        assert_eq!("(list 1)", editor.line());
+
+        editor.handle(Key::Home);
+        editor.handle(Key::Delete);
+        assert_eq!("list 1)", editor.line());
+        assert_eq!(Edit::Submit, editor.handle(Key::Enter));
+    }
+
+    #[test]
+    fn test_interrupt_and_eof() {
+        let mut editor = LineEditor::new(&[]);
+        editor.handle(Key::Char('x'));
+        assert_eq!(Edit::Continue, editor.handle(Key::Eof));
+        assert_eq!("x", editor.line());
+        editor.handle(Key::Interrupt);
+        assert_eq!("", editor.line());
+        assert_eq!(Edit::Eof, editor.handle(Key::Eof));
+    }
+
+    #[test]
+    fn test_wide_characters_and_scrolling() {
+        assert_eq!(1, char_width('a'));
+        assert_eq!(2, char_width('語'));
+        assert_eq!(0, char_width('\u{301}'));
+
+        let mut editor = LineEditor::new(&[]);
+        for c in "日本語".chars() {
+            editor.handle(Key::Char(c));
+        }
+        editor.handle(Key::Left);
+        let mut out = Vec::new();
+        editor.redraw("> ", 80, &mut out);
+        // The cursor goes back over one wide character: two columns.
+        assert_eq!("\r> 日本語\x1b[K\x1b[2D", String::from_utf8(out).unwrap());
+
+        let mut editor = LineEditor::new(&[]);
+        for c in "abcdefghij".chars() {
+            editor.handle(Key::Char(c));
+        }
+        // Seven columns leave four for the line after "> " and the cursor.
+        let mut out = Vec::new();
+        editor.redraw("> ", 7, &mut out);
+        assert_eq!("\r> ghij\x1b[K", String::from_utf8(out).unwrap());
+        editor.handle(Key::Home);
+        let mut out = Vec::new();
+        editor.redraw("> ", 7, &mut out);
+        assert_eq!("\r> abcd\x1b[K\x1b[4D", String::from_utf8(out).unwrap());
+    }
+}
@@ -1,35 +1,47 @@
-use runtime::Runtime;
-use std::io::{stdin, BufRead};
+use nom_lisp::runtime::{error::LispError, Runtime};
+use std::{
+    io::{stdin, stdout, IsTerminal},
+    process::exit,
+};
 
-mod ast;
-mod parser;
-mod runtime;
+mod line_editor;
+mod repl;
 
 fn main() {
     let mut runtime = Runtime::try_new().unwrap();
-    'mainloop: loop {
-        let mut buf = String::new();
-        let node = loop {
-            let line = stdin().lock().lines().next().unwrap().unwrap();
-            buf.push_str(&line);
-            buf.push('\n');
-            let parsed = parser::node(buf.as_bytes());
-            match parsed {
-                Ok((_, node)) => break node,
-                Err(e) => match e {
-                    nom::Err::Incomplete(_) => continue,
-                    _ => {
-                        println!("{:?}", e);
-                        continue 'mainloop;
-                    }
-                },
-            }
-        };
+    let mut args = std::env::args().skip(1);
+    match args.next() {
+        Some(path) => {
+            runtime.set_args(args.collect());
+            run_script(&mut runtime, &path);
+        }
+        None if stdin().is_terminal() => {
+            let mut reader = line_editor::TtyReader::new();
+            repl::run(&mut runtime, &mut reader, &mut stdout());
+        }
+        None => {
+            let mut reader = repl::StdinReader::new();
+            repl::run(&mut runtime, &mut reader, &mut stdout());
+        }
+    }
+}
 
-        let result = runtime.eval(node);
-        match result {
-            Ok(r) => println!("{r:?}"),
-            Err(e) => println!("Error: {e}"),
+fn run_script(runtime: &mut Runtime, path: &str) {
+    let src = match std::fs::read_to_string(path) {
+        Ok(src) => src,
+        Err(e) => {
+            eprintln!("Couldn't read {path}: {e}");
+            exit(1);
+        }
+    };
+    let result = runtime.eval_program_str(&src);
+    let flushed = runtime.flush();
+    match result.and(flushed) {
+        Ok(_) => {}
+        Err(LispError::Exit(code)) => exit(code),
+        Err(e) => {
+            eprintln!("Error: {}", e.with_context(&src));
+            exit(1);
         }
     }
 }
@@ -1,38 +1,340 @@
-use crate::ast::Node;
+use std::{cell::Cell, collections::HashMap};
+
+use crate::{
+    ast::{Node, Spanned},
+    lexer::{
+        comment, is_identifier_body, is_identifier_start, is_separator, separator0, separator1,
+        whitespace, Span,
+    },
+    runtime::error::LispError,
+};
 use nom::{
     branch::alt,
     bytes::streaming::{escaped, tag, take_while, take_while1},
-    character::{is_alphabetic, is_digit, is_newline, is_space, streaming::one_of},
-    combinator::recognize,
-    error::context,
-    multi::separated_list1,
-    sequence::{delimited, pair, preceded, terminated},
+    character::{is_digit, streaming::one_of},
+    combinator::{not, opt, recognize},
+    error::{context, ErrorKind},
+    multi::many0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
     IResult,
 };
 
-fn is_separator(c: u8) -> bool {
-    is_space(c) || is_newline(c)
+/// Parses every top-level form of a complete source text.
+pub fn program(input: &[u8]) -> Result<Vec<Node>, LispError> {
+    program_with_options(input, &ParseOptions::default())
+}
+
+/// What `program_with_options` accepts beyond the default syntax.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOptions {
+    /// Whether a top-level form may be something other than a list. A lone
+    /// identifier or literal does nothing in a program, so embedders may
+    /// want to treat one as the typo it usually is.
+    pub allow_bare_atoms: bool,
+    /// Whether comments are kept as `Node::Comment`, both between top-level
+    /// forms and inside lists.
+    pub keep_comments: bool,
+}
+
+impl Default for ParseOptions {
+    fn default() -> Self {
+        Self {
+            allow_bare_atoms: true,
+            keep_comments: false,
+        }
+    }
 }
 
-fn is_identifier_start(c: u8) -> bool {
-    is_alphabetic(c)
-        || c == b'+'
-        || c == b'-'
-        || c == b'*'
-        || c == b'/'
-        || c == b'_'
-        || c == b'='
-        || c == b'>'
-        || c == b'<'
-        || c == b'!'
+pub fn program_with_options(input: &[u8], options: &ParseOptions) -> Result<Vec<Node>, LispError> {
+    // The parsers are streaming, so a trailing newline lets a final bare atom
+    // end instead of waiting for more input.
+    let mut buf = input.to_vec();
+    buf.push(b'\n');
+    let mut rest = buf.as_slice();
+    let mut nodes = Vec::new();
+    let mut parser = Parser::new();
+    parser.set_keep_comments(options.keep_comments);
+    loop {
+        rest = match parser.separators(rest, &mut nodes) {
+            Ok((next, _)) => next,
+            // Only separators can run into the end of the input here.
+            Err(nom::Err::Incomplete(_)) => &rest[rest.len()..],
+            Err(_) => rest,
+        };
+        if rest.is_empty() {
+            return Ok(nodes);
+        }
+        let (next, node) = parser.node(rest).map_err(|e| syntax_error(&buf, e))?;
+        if !options.allow_bare_atoms && !matches!(node, Node::List(_)) {
+            return Err(LispError::SyntaxError(format!(
+                "Expected a list at byte {}, found {}.",
+                buf.len() - rest.len(),
+                node.to_source()
+            )));
+        }
+        nodes.push(node);
+        rest = next;
+    }
 }
 
-fn is_identifier_body(c: u8) -> bool {
-    is_identifier_start(c) || is_digit(c)
+/// A syntax error and the stretch of source it covers.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+}
+
+/// Like `program`, but keeps going after a broken form so that every error
+/// can be reported at once. Returns the forms that parsed, each with its
+/// span, and an error for each form that didn't.
+pub fn program_recovering(input: &[u8]) -> (Vec<Spanned>, Vec<ParseError>) {
+    let mut buf = input.to_vec();
+    buf.push(b'\n');
+    let mut rest = buf.as_slice();
+    let mut nodes = Vec::new();
+    let mut errors = Vec::new();
+    loop {
+        rest = skip_separators(rest);
+        if rest.is_empty() {
+            return (nodes, errors);
+        }
+        let start = buf.len() - rest.len();
+        match node(rest) {
+            Ok((next, node)) => {
+                nodes.push(Spanned {
+                    node,
+                    span: start..buf.len() - next.len(),
+                });
+                rest = next;
+            }
+            Err(e) => {
+                let end = (start + skip_form(rest)).min(input.len());
+                let message = match syntax_error(&buf, e) {
+                    LispError::SyntaxError(message) => message,
+                    e => e.to_string(),
+                };
+                errors.push(ParseError {
+                    span: start..end,
+                    message,
+                });
+                rest = &buf[end.max(start + 1)..];
+            }
+        }
+    }
+}
+
+// Finds the end of a broken form by balancing parentheses, so parsing can
+// resume with the next top-level form.
+fn skip_form(input: &[u8]) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    while i < input.len() {
+        match input[i] {
+            b'"' => {
+                i += 1;
+                while i < input.len() && input[i] != b'"' {
+                    if input[i] == b'\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+            }
+            b';' => {
+                while i + 1 < input.len() && input[i + 1] != b'\n' {
+                    i += 1;
+                }
+            }
+            b'(' => depth += 1,
+            b')' if depth <= 1 => return i + 1,
+            b')' => depth -= 1,
+            c if depth == 0 && is_separator(c) => return i,
+            _ => {}
+        }
+        i += 1;
+    }
+    input.len()
+}
+
+/// Parses a single form, returning it with the number of bytes consumed.
+pub fn parse_one(input: &[u8]) -> Result<(Node, usize), LispError> {
+    let (rest, node) = node(input).map_err(|e| syntax_error(input, e))?;
+    Ok((node, input.len() - rest.len()))
+}
+
+fn syntax_error(input: &[u8], e: nom::Err<nom::error::Error<&[u8]>>) -> LispError {
+    match e {
+        nom::Err::Incomplete(_) => LispError::SyntaxError("Unexpected end of input.".into()),
+        nom::Err::Failure(e) if e.code == ErrorKind::MapRes => LispError::SyntaxError(format!(
+            "Integer literal out of range at byte {}.",
+            input.len() - e.input.len()
+        )),
+        nom::Err::Error(e) | nom::Err::Failure(e) => LispError::SyntaxError(format!(
+            "Unexpected input at byte {}.",
+            input.len() - e.input.len()
+        )),
+    }
+}
+
+fn skip_separators(mut input: &[u8]) -> &[u8] {
+    loop {
+        match separator1(input) {
+            Ok((rest, _)) => input = rest,
+            // Only separators can run into the end of the input here.
+            Err(nom::Err::Incomplete(_)) => return &input[input.len()..],
+            Err(_) => return input,
+        }
+    }
 }
 
 pub fn node(input: &[u8]) -> IResult<&[u8], Node> {
-    alt((identifier, list, string_literal, integer_literal, quote))(input)
+    Parser::default().node(input)
+}
+
+/// Reads the rest of a form after the character that triggered it.
+pub type ReaderMacro = fn(&[u8]) -> IResult<&[u8], Node>;
+
+/// A parser extended with reader macros. The free functions in this module
+/// parse the default syntax, which is what a `Parser` with no reader macros
+/// accepts too.
+pub struct Parser {
+    reader_macros: HashMap<char, ReaderMacro>,
+    max_depth: usize,
+    depth: Cell<usize>,
+    keep_comments: bool,
+}
+
+impl Default for Parser {
+    fn default() -> Self {
+        Self {
+            reader_macros: HashMap::new(),
+            max_depth: DEFAULT_MAX_DEPTH,
+            depth: Cell::new(0),
+            keep_comments: false,
+        }
+    }
+}
+
+/// How deeply lists and quotes may nest before parsing fails, keeping deeply
+/// nested input from overflowing the native stack.
+pub const DEFAULT_MAX_DEPTH: usize = 1000;
+
+impl Parser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_max_depth(&mut self, max_depth: usize) {
+        self.max_depth = max_depth;
+    }
+
+    /// Makes comments inside lists show up as `Node::Comment` elements
+    /// instead of being skipped, for tools like formatters that need to
+    /// reproduce them.
+    pub fn set_keep_comments(&mut self, keep_comments: bool) {
+        self.keep_comments = keep_comments;
+    }
+
+    /// Makes every form starting with `c` (an ASCII character) get read by
+    /// `f` instead, including forms nested in lists and quotes.
+    pub fn register_reader_macro(&mut self, c: char, f: ReaderMacro) {
+        self.reader_macros.insert(c, f);
+    }
+
+    pub fn node<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], Node> {
+        if let Some(f) = input
+            .first()
+            .and_then(|b| self.reader_macros.get(&char::from(*b)))
+        {
+            return f(&input[1..]);
+        }
+        // Dispatching on the first byte rather than trying each alternative
+        // keeps the stack frames small for nested forms.
+        match input.first() {
+            Some(b'(') => self.list(input),
+            Some(b'\'') => self.quote(input),
+            // Numbers go before identifiers so that -5 is a number while -
+            // and -> stay identifiers.
+            _ => alt((string_literal, float_literal, integer_literal, identifier))(input),
+        }
+    }
+
+    pub fn quote<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], Node> {
+        let (input, node) = self.nested(input, |input| {
+            context("Quote", preceded(tag("'"), |i| self.node(i)))(input)
+        })?;
+        Ok((input, Node::Quote(Box::new(node))))
+    }
+
+    // Written out by hand instead of with separated_list1, again to keep the
+    // frames of nested lists small.
+    pub fn list<'a>(&self, input: &'a [u8]) -> IResult<&'a [u8], Node> {
+        self.nested(input, |input| {
+            let (input, _) = tag("(")(input)?;
+            let mut nodes = Vec::new();
+            let (mut input, _) = self.separators(input, &mut nodes)?;
+            loop {
+                let (rest, node) = self.node(input)?;
+                nodes.push(node);
+                let (rest, separators) = self.separators(rest, &mut nodes)?;
+                if rest.first() == Some(&b')') {
+                    return Ok((&rest[1..], Node::List(nodes)));
+                }
+                if separators == 0 && !rest.is_empty() {
+                    return Err(nom::Err::Error(nom::error::Error::new(
+                        rest,
+                        ErrorKind::Tag,
+                    )));
+                }
+                input = rest;
+            }
+        })
+    }
+
+    // Skips whitespace and comments like separator0, moving the comments
+    // into nodes if they are being kept.
+    fn separators<'a>(
+        &self,
+        mut input: &'a [u8],
+        nodes: &mut Vec<Node>,
+    ) -> IResult<&'a [u8], usize> {
+        if !self.keep_comments {
+            return separator0(input);
+        }
+        let mut count = 0;
+        loop {
+            match alt((whitespace, comment))(input) {
+                Ok((rest, skipped)) => {
+                    if let Some(text) = skipped.strip_prefix(b";") {
+                        nodes.push(Node::Comment(String::from_utf8_lossy(text).into_owned()));
+                    }
+                    count += 1;
+                    input = rest;
+                }
+                Err(nom::Err::Error(_)) => return Ok((input, count)),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    // Runs f one nesting level deeper, failing outright past the limit so
+    // that alternatives aren't retried at every level on the way out.
+    fn nested<'a, T>(
+        &self,
+        input: &'a [u8],
+        f: impl FnOnce(&'a [u8]) -> IResult<&'a [u8], T>,
+    ) -> IResult<&'a [u8], T> {
+        let depth = self.depth.get();
+        if depth >= self.max_depth {
+            return Err(nom::Err::Failure(nom::error::Error::new(
+                input,
+                ErrorKind::TooLarge,
+            )));
+        }
+        self.depth.set(depth + 1);
+        let r = f(input);
+        self.depth.set(depth);
+        r
+    }
 }
 
 pub fn identifier(input: &[u8]) -> IResult<&[u8], Node> {
@@ -48,20 +350,11 @@ pub fn identifier(input: &[u8]) -> IResult<&[u8], Node> {
 }
 
 pub fn quote(input: &[u8]) -> IResult<&[u8], Node> {
-    let (input, node) = context("Quote", preceded(tag("'"), node))(input)?;
-    Ok((input, Node::Quote(Box::new(node))))
+    Parser::default().quote(input)
 }
 
 pub fn list(input: &[u8]) -> IResult<&[u8], Node> {
-    let (input, nodes) = context(
-        "list",
-        delimited(
-            terminated(tag("("), take_while(is_separator)),
-            separated_list1(take_while1(is_separator), node),
-            preceded(take_while(is_separator), tag(")")),
-        ),
-    )(input)?;
-    Ok((input, Node::List(nodes)))
+    Parser::default().list(input)
 }
 
 pub fn string_literal(input: &[u8]) -> IResult<&[u8], Node> {
@@ -79,16 +372,55 @@ pub fn string_literal(input: &[u8]) -> IResult<&[u8], Node> {
     ))
 }
 
+pub fn float_literal(input: &[u8]) -> IResult<&[u8], Node> {
+    let (input, span) = context(
+        "Float literal",
+        recognize(tuple((
+            opt(tag("-")),
+            take_while1(is_digit),
+            tag("."),
+            take_while1(is_digit),
+        ))),
+    )(input)?;
+    let fstr = std::str::from_utf8(span).unwrap();
+    let f = fstr.parse().unwrap();
+    Ok((input, Node::FloatLiteral(f)))
+}
+
+// Underscores may separate digits, as in 1_000, but can't lead, trail or
+// repeat. A leading - makes the literal negative. Literals too big for an
+// Int fail outright rather than wrapping.
 pub fn integer_literal(input: &[u8]) -> IResult<&[u8], Node> {
-    let (input, span) = context("Integer literal", take_while1(is_digit))(input)?;
-    let ustr = std::str::from_utf8(span).unwrap();
-    let i = ustr.parse().unwrap();
-    Ok((input, Node::IntegerLiteral(i)))
+    let start = input;
+    let (input, span) = context(
+        "Integer literal",
+        terminated(
+            recognize(tuple((
+                opt(tag("-")),
+                take_while1(is_digit),
+                many0(preceded(tag("_"), take_while1(is_digit))),
+            ))),
+            not(tag("_")),
+        ),
+    )(input)?;
+    let digits: String = std::str::from_utf8(span).unwrap().replace('_', "");
+    match digits.parse() {
+        Ok(i) => Ok((input, Node::IntegerLiteral(i))),
+        Err(_) => Err(nom::Err::Failure(nom::error::Error::new(
+            start,
+            ErrorKind::MapRes,
+        ))),
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::parser::{node, Node};
+    use crate::parser::{
+        integer_literal, node, parse_one, program, program_recovering, program_with_options, Node,
+        ParseOptions, Parser, DEFAULT_MAX_DEPTH,
+    };
+    use crate::runtime::error::LispError;
+    use nom::{bytes::streaming::tag, combinator::value, IResult};
 
     fn assert_parses_into(expect: Node, input: &[u8]) {
         let (input, output) = node(input).unwrap();
@@ -126,6 +458,190 @@ mod test {
         )
     }
 
+    #[test]
+    fn test_identifier() {
+        assert_parses_into(
+            Node::List(vec![
+                Node::Identifier("starts-with?".into()),
+                Node::Identifier("set!".into()),
+                Node::Identifier("->>".into()),
+            ]),
+            b"(starts-with? set! ->>)",
+        );
+    }
+
+    #[test]
+    fn test_program() {
+        assert_eq!(
+            vec![
+                Node::List(vec![Node::Identifier("a".into())]),
+                Node::IntegerLiteral(5),
+                Node::Identifier("b".into()),
+            ],
+            program(b"; header\n(a)\n 5 b").unwrap()
+        );
+        assert_eq!(Vec::<Node>::new(), program(b"  ; only a comment").unwrap());
+        assert!(program(b"(a b").is_err());
+        assert!(program(b"(a) )").is_err());
+    }
+
+    #[test]
+    fn test_program_recovering() {
+        let src = b"(a 1) (b \")\" 2.x) ) (c)";
+        let (nodes, errors) = program_recovering(src);
+        let nodes: Vec<_> = nodes.into_iter().map(|n| (n.node, n.span)).collect();
+        assert_eq!(
+            vec![
+                (
+                    Node::List(vec![Node::Identifier("a".into()), Node::IntegerLiteral(1)]),
+                    0..5
+                ),
+                (Node::List(vec![Node::Identifier("c".into())]), 20..23),
+            ],
+            nodes
+        );
+        let spans: Vec<_> = errors.into_iter().map(|e| e.span).collect();
+        assert_eq!(vec![6..17, 18..19], spans);
+
+        let (nodes, errors) = program_recovering(b"(ok) (unclosed");
+        assert_eq!(1, nodes.len());
+        assert_eq!(5..14, errors[0].span);
+        assert_eq!("Unexpected end of input.", errors[0].message);
+    }
+
+    #[test]
+    fn test_parse_one() {
+        let input = b"(a b) rest";
+        let (node, consumed) = parse_one(input).unwrap();
+        assert_eq!(
+            Node::List(vec![
+                Node::Identifier("a".into()),
+                Node::Identifier("b".into())
+            ]),
+            node
+        );
+        assert_eq!(b" rest", &input[consumed..]);
+        assert!(parse_one(b"(a").is_err());
+    }
+
+    fn read_bool(input: &[u8]) -> IResult<&[u8], Node> {
+        nom::branch::alt((
+            value(Node::BoolLiteral(true), tag("t")),
+            value(Node::BoolLiteral(false), tag("f")),
+        ))(input)
+    }
+
+    #[test]
+    fn test_reader_macro() {
+        let mut parser = Parser::new();
+        parser.register_reader_macro('#', read_bool);
+        assert_eq!(Node::BoolLiteral(true), parser.node(b"#t").unwrap().1);
+        assert_eq!(
+            Node::List(vec![
+                Node::Identifier("if".into()),
+                Node::BoolLiteral(false),
+                Node::Quote(Box::new(Node::BoolLiteral(true))),
+            ]),
+            parser.node(b"(if #f '#t)").unwrap().1
+        );
+        assert!(node(b"#t").is_err());
+    }
+
+    #[test]
+    fn test_nesting_limit() {
+        let deep = |n| format!("{}a{}", "(".repeat(n), ")".repeat(n));
+        assert!(node(deep(DEFAULT_MAX_DEPTH).as_bytes()).is_ok());
+        assert!(matches!(
+            node(deep(2000).as_bytes()),
+            Err(nom::Err::Failure(_))
+        ));
+        assert!(parse_one(deep(2000).as_bytes()).is_err());
+
+        let mut parser = Parser::new();
+        parser.set_max_depth(2);
+        assert!(parser.node(b"('a)").is_ok());
+        assert!(parser.node(b"(('a))").is_err());
+    }
+
+    #[test]
+    fn test_keep_comments() {
+        let mut parser = Parser::new();
+        parser.set_keep_comments(true);
+        let (_, kept) = parser.node(b"(a ; hi\n b)").unwrap();
+        assert_eq!(
+            Node::List(vec![
+                Node::Identifier("a".into()),
+                Node::Comment(" hi".into()),
+                Node::Identifier("b".into()),
+            ]),
+            kept
+        );
+        assert_eq!("(a ; hi\n b)", kept.to_source());
+        assert_eq!(
+            Node::List(vec![
+                Node::Identifier("a".into()),
+                Node::Identifier("b".into())
+            ]),
+            node(b"(a ; hi\n b)").unwrap().1
+        );
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_parses_into(
+            Node::List(vec![
+                Node::Identifier("a".into()),
+                Node::Identifier("b".into()),
+            ]),
+            b"( ; leading
+ a ; between
+
+ b ; trailing
+)",
+        );
+    }
+
+    #[test]
+    fn test_float() {
+        assert_parses_into(
+            Node::List(vec![
+                Node::FloatLiteral(3.9),
+                Node::IntegerLiteral(3),
+                Node::FloatLiteral(0.5),
+            ]),
+            b"(3.9 3 0.5)",
+        );
+    }
+
+    #[test]
+    fn test_negative_literals() {
+        assert_parses_into(
+            Node::List(vec![
+                Node::IntegerLiteral(-5),
+                Node::FloatLiteral(-2.5),
+                Node::Identifier("-".into()),
+                Node::Identifier("->".into()),
+                Node::IntegerLiteral(i32::MIN),
+            ]),
+            b"(-5 -2.5 - -> -2147483648)",
+        );
+    }
+
+    #[test]
+    fn test_integer_separators() {
+        assert_parses_into(
+            Node::List(vec![
+                Node::IntegerLiteral(1000),
+                Node::IntegerLiteral(1_000_000),
+            ]),
+            b"(1_000 1_000_000)",
+        );
+        assert!(integer_literal(b"_1 ").is_err());
+        assert!(integer_literal(b"1_ ").is_err());
+        assert!(integer_literal(b"1__0 ").is_err());
+        assert!(node(b"(1_)").is_err());
+    }
+
     #[test]
     fn test_quote() {
         assert_parses_into(
@@ -139,4 +655,56 @@ mod test {
             b"('(1) '1 '\"x\" ''1 'deadbeef)",
         );
     }
+
+    #[test]
+    fn test_integer_out_of_range() {
+        let (_, max) = integer_literal(b"2147483647 ").unwrap();
+        assert_eq!(Node::IntegerLiteral(i32::MAX), max);
+        assert!(matches!(
+            integer_literal(b"3000000000 "),
+            Err(nom::Err::Failure(_))
+        ));
+        match program(b"(+ 1 3000000000)") {
+            Err(LispError::SyntaxError(msg)) => {
+                assert_eq!("Integer literal out of range at byte 5.", msg)
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+    }
+
+    #[test]
+    fn test_parse_options() {
+        let src = b"(let x 1) x";
+        assert_eq!(2, program(src).unwrap().len());
+        let lenient = ParseOptions::default();
+        assert_eq!(2, program_with_options(src, &lenient).unwrap().len());
+        let strict = ParseOptions {
+            allow_bare_atoms: false,
+            ..ParseOptions::default()
+        };
+        match program_with_options(src, &strict) {
+            Err(LispError::SyntaxError(msg)) => {
+                assert_eq!("Expected a list at byte 10, found x.", msg)
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(program_with_options(b"(a) (b (c))", &strict).is_ok());
+
+        let commented = ParseOptions {
+            keep_comments: true,
+            ..ParseOptions::default()
+        };
+        assert_eq!(
+            vec![
+                Node::Comment(" top".into()),
+                Node::List(vec![
+                    Node::Identifier("a".into()),
+                    Node::Comment(" in".into()),
+                ]),
+                Node::Comment(" end".into()),
+            ],
+            program_with_options(b"; top\n(a ; in\n) ; end", &commented).unwrap()
+        );
+        assert_eq!(1, program(b"; top\n(a ; in\n) ; end").unwrap().len());
+    }
 }
@@ -0,0 +1,314 @@
+use nom_lisp::{
+    parser,
+    runtime::{error::LispError, Runtime},
+};
+use std::{
+    fs::{File, OpenOptions},
+    io::{stdin, stdout, BufRead, BufReader, Write},
+    path::PathBuf,
+};
+
+/// A source of REPL input lines that remembers what was entered.
+pub trait LineReader {
+    /// Reads the next line, first showing `prompt` if it isn't empty.
+    fn read_line(&mut self, prompt: &str) -> Option<String>;
+    fn add_history(&mut self, entry: &str);
+    fn history(&self) -> &[String];
+}
+
+/// Reads from stdin and appends every complete entry to a history file, so
+/// earlier sessions can be recalled with `:history`.
+pub struct StdinReader {
+    history: Vec<String>,
+    history_file: Option<PathBuf>,
+}
+
+impl StdinReader {
+    pub fn new() -> Self {
+        let history_file =
+            std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".nom_lisp_history"));
+        let history = history_file
+            .as_ref()
+            .and_then(|path| File::open(path).ok())
+            .map(|file| {
+                BufReader::new(file)
+                    .lines()
+                    .map_while(|line| line.ok())
+                    .map(|line| unescape_entry(&line))
+                    .collect()
+            })
+            .unwrap_or_default();
+        Self {
+            history,
+            history_file,
+        }
+    }
+}
+
+impl LineReader for StdinReader {
+    fn read_line(&mut self, prompt: &str) -> Option<String> {
+        if !prompt.is_empty() {
+            print!("{prompt}");
+            let _ = stdout().flush();
+        }
+        stdin().lock().lines().next()?.ok()
+    }
+
+    fn add_history(&mut self, entry: &str) {
+        self.history.push(entry.into());
+        // History is a convenience; failing to persist it shouldn't stop the REPL.
+        if let Some(path) = &self.history_file {
+            if let Ok(mut file) = OpenOptions::new().create(true).append(true).open(path) {
+                let _ = writeln!(file, "{}", escape_entry(entry));
+            }
+        }
+    }
+
+    fn history(&self) -> &[String] {
+        &self.history
+    }
+}
+
+// Multi-line entries are stored one per line in the history file.
+fn escape_entry(entry: &str) -> String {
+    entry.replace('\\', "\\\\").replace('\n', "\\n")
+}
+
+fn unescape_entry(line: &str) -> String {
+    let mut entry = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match (c, chars.clone().next()) {
+            ('\\', Some('n')) => {
+                entry.push('\n');
+                chars.next();
+            }
+            ('\\', Some('\\')) => {
+                entry.push('\\');
+                chars.next();
+            }
+            _ => entry.push(c),
+        }
+    }
+    entry
+}
+
+/// How many lists are still open at the end of `src`. Parens inside string
+/// literals and comments don't count.
+pub fn open_depth(src: &str) -> usize {
+    let mut depth = 0usize;
+    let (mut in_string, mut in_comment) = (false, false);
+    for c in src.chars() {
+        match c {
+            '\n' if in_comment => in_comment = false,
+            _ if in_comment => {}
+            '"' => in_string = !in_string,
+            _ if in_string => {}
+            ';' => in_comment = true,
+            '(' => depth += 1,
+            ')' => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    depth
+}
+
+// Shown while a form is unfinished, with the number of lists left to close.
+fn continuation_prompt(buf: &str) -> String {
+    format!("..({}). ", open_depth(buf))
+}
+
+/// How many items of a list result are shown before the rest is elided,
+/// until changed with `:limit`.
+pub const DEFAULT_DISPLAY_LIMIT: usize = 100;
+
+/// Lists the user's definitions with their types, like `square : function`.
+pub fn write_defs(runtime: &Runtime, out: &mut impl Write) {
+    for (name, data) in runtime.user_bindings() {
+        writeln!(out, "{name} : {}", data.type_name()).unwrap();
+    }
+}
+
+pub fn run(runtime: &mut Runtime, reader: &mut impl LineReader, out: &mut impl Write) {
+    let mut display_limit = DEFAULT_DISPLAY_LIMIT;
+    'mainloop: loop {
+        let mut buf = String::new();
+        let node = loop {
+            let prompt = if buf.is_empty() {
+                String::new()
+            } else {
+                continuation_prompt(&buf)
+            };
+            let Some(line) = reader.read_line(&prompt) else {
+                return;
+            };
+            if buf.is_empty() && line.trim_start().starts_with(':') {
+                reader.add_history(line.trim());
+                match line.trim() {
+                    ":quit" => return,
+                    ":reset" => match runtime.reset() {
+                        Ok(()) => writeln!(out, "Environment reset.").unwrap(),
+                        Err(e) => writeln!(out, "Error: {e}").unwrap(),
+                    },
+                    ":env" => {
+                        for (name, data) in runtime.bindings() {
+                            writeln!(out, "{name} = {data:?}").unwrap();
+                        }
+                    }
+                    ":defs" => write_defs(runtime, out),
+                    ":history" => {
+                        for entry in reader.history() {
+                            writeln!(out, "{entry}").unwrap();
+                        }
+                    }
+                    cmd if cmd.starts_with(":limit") => {
+                        match cmd[":limit".len()..].trim().parse() {
+                            Ok(limit) => display_limit = limit,
+                            Err(_) => writeln!(out, "Usage: :limit <items>").unwrap(),
+                        }
+                    }
+                    cmd => writeln!(out, "Unknown command {cmd}").unwrap(),
+                }
+                continue 'mainloop;
+            }
+            buf.push_str(&line);
+            buf.push('\n');
+            let parsed = parser::node(buf.as_bytes());
+            match parsed {
+                Ok((_, node)) => break node,
+                Err(e) => match e {
+                    nom::Err::Incomplete(_) => continue,
+                    _ => {
+                        reader.add_history(buf.trim_end());
+                        writeln!(out, "{:?}", e).unwrap();
+                        continue 'mainloop;
+                    }
+                },
+            }
+        };
+        reader.add_history(buf.trim_end());
+
+        let result = runtime.eval(node);
+        // Printed output comes before the result it led to.
+        if let Err(e) = runtime.flush() {
+            writeln!(out, "Error: {e}").unwrap();
+        }
+        match result {
+            Ok(r) => writeln!(out, "{}", r.display_truncated(display_limit)).unwrap(),
+            Err(LispError::Exit(_)) => writeln!(out, "Use :quit to leave the REPL.").unwrap(),
+            Err(e) => writeln!(out, "Error: {}", e.with_context(&buf)).unwrap(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        escape_entry, open_depth, run, unescape_entry, write_defs, LineReader,
+        DEFAULT_DISPLAY_LIMIT,
+    };
+    use nom_lisp::runtime::Runtime;
+    use std::collections::VecDeque;
+
+    struct ScriptedReader {
+        lines: VecDeque<String>,
+        history: Vec<String>,
+        prompts: Vec<String>,
+    }
+
+    impl ScriptedReader {
+        fn new(lines: &[&str]) -> Self {
+            Self {
+                lines: lines.iter().map(|&line| line.into()).collect(),
+                history: Vec::new(),
+                prompts: Vec::new(),
+            }
+        }
+    }
+
+    impl LineReader for ScriptedReader {
+        fn read_line(&mut self, prompt: &str) -> Option<String> {
+            self.prompts.push(prompt.into());
+            self.lines.pop_front()
+        }
+
+        fn add_history(&mut self, entry: &str) {
+            self.history.push(entry.into());
+        }
+
+        fn history(&self) -> &[String] {
+            &self.history
+        }
+    }
+
+    #[test]
+    fn test_run_evaluates_form() {
+        let mut runtime = Runtime::try_new().unwrap();
+        let mut reader = ScriptedReader::new(&["(+ 1", "2)"]);
+        let mut out = Vec::new();
+        run(&mut runtime, &mut reader, &mut out);
+        assert_eq!("3\n", String::from_utf8(out).unwrap());
+        assert_eq!(vec!["(+ 1\n2)".to_string()], reader.history);
+    }
+
+    #[test]
+    fn test_history_escaping() {
+        let entry = "(debug\n \"a\\nb\")";
+        assert!(!escape_entry(entry).contains('\n'));
+        assert_eq!(entry, unescape_entry(&escape_entry(entry)));
+    }
+
+    #[test]
+    fn test_large_results_are_truncated() {
+        let mut runtime = Runtime::try_new().unwrap();
+        let mut reader = ScriptedReader::new(&[
+            "(repeat 10000 7)",
+            ":limit 3",
+            "(list 1 (list 2 3 4 5) 6 7)",
+        ]);
+        let mut out = Vec::new();
+        run(&mut runtime, &mut reader, &mut out);
+        let out = String::from_utf8(out).unwrap();
+        let mut lines = out.lines();
+        let shown = vec!["7"; DEFAULT_DISPLAY_LIMIT].join(" ");
+        let expected = format!("({shown} ... {} more)", 10000 - DEFAULT_DISPLAY_LIMIT);
+        assert_eq!(Some(expected.as_str()), lines.next());
+        assert_eq!(Some("(1 (2 3 4 ... 1 more) 6 ... 1 more)"), lines.next());
+    }
+
+    #[test]
+    fn test_defs_lists_user_definitions() {
+        let mut runtime = Runtime::try_new().unwrap();
+        runtime
+            .eval_program_str("(defn square (x) (* x x)) (let x 5)")
+            .unwrap();
+        let mut out = Vec::new();
+        write_defs(&runtime, &mut out);
+        assert_eq!(
+            "square : function\nx : int\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_open_depth() {
+        assert_eq!(0, open_depth(""));
+        assert_eq!(2, open_depth("(let x (list 1"));
+        assert_eq!(1, open_depth("(f (g)"));
+        assert_eq!(0, open_depth("(f (g))"));
+        assert_eq!(1, open_depth("(debug \"(((\""));
+        assert_eq!(1, open_depth("(debug \"a)\" ; ))\n"));
+        assert_eq!(2, open_depth("(f \"(\"\n  (g"));
+        assert_eq!(0, open_depth("))"));
+    }
+
+    #[test]
+    fn test_continuation_prompt_shows_depth() {
+        let mut runtime = Runtime::try_new().unwrap();
+        let mut reader = ScriptedReader::new(&["(list (list 1", "\")\"", ") 2)"]);
+        let mut out = Vec::new();
+        run(&mut runtime, &mut reader, &mut out);
+        assert_eq!("((1 \")\") 2)\n", String::from_utf8(out).unwrap());
+        assert_eq!(vec!["", "..(2). ", "..(2). ", ""], reader.prompts);
+    }
+}
@@ -0,0 +1,83 @@
+//! Type checks for intrinsic arguments. `what` names the offending value in
+//! the error, usually by the source of the expression that produced it.
+
+use super::{
+    error::{LispError, Result},
+    list::List,
+    Data,
+};
+
+pub fn expect_int(d: &Data, what: &str) -> Result<i32> {
+    match d {
+        Data::Int(i) => Ok(*i),
+        _ => Err(mismatch(what, "an integer")),
+    }
+}
+
+/// Accepts integers as well as floats, widening the former.
+pub fn expect_number(d: &Data, what: &str) -> Result<f64> {
+    match d {
+        Data::Int(i) => Ok(*i as f64),
+        Data::Float(f) => Ok(*f),
+        _ => Err(mismatch(what, "a number")),
+    }
+}
+
+pub fn expect_str<'a>(d: &'a Data, what: &str) -> Result<&'a str> {
+    match d {
+        Data::Str(s) => Ok(s),
+        _ => Err(mismatch(what, "a string")),
+    }
+}
+
+pub fn expect_list<'a>(d: &'a Data, what: &str) -> Result<&'a List> {
+    match d {
+        Data::List(items) => Ok(items),
+        _ => Err(mismatch(what, "a list")),
+    }
+}
+
+fn mismatch(what: &str, expected: &str) -> LispError {
+    LispError::TypeError(format!("{what} is not {expected}."))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expect_int, expect_list, expect_number, expect_str};
+    use crate::runtime::Data;
+
+    #[test]
+    fn test_expect_wrong_types() {
+        assert_eq!(
+            "Type error: x is not an integer.",
+            expect_int(&Data::Float(1.5), "x").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Type error: \"a\" is not a number.",
+            expect_number(&Data::Str("a".into()), "\"a\"")
+                .unwrap_err()
+                .to_string()
+        );
+        assert_eq!(
+            "Type error: 1 is not a string.",
+            expect_str(&Data::Int(1), "1").unwrap_err().to_string()
+        );
+        assert_eq!(
+            "Type error: (f) is not a list.",
+            expect_list(&Data::Empty, "(f)").unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn test_expect_right_types() {
+        assert_eq!(3, expect_int(&Data::Int(3), "x").unwrap());
+        assert_eq!(3.0, expect_number(&Data::Int(3), "x").unwrap());
+        assert_eq!("s", expect_str(&Data::Str("s".into()), "x").unwrap());
+        assert_eq!(
+            vec![Data::Int(1)],
+            expect_list(&Data::list(vec![Data::Int(1)]), "x")
+                .unwrap()
+                .to_vec()
+        );
+    }
+}
@@ -1,5 +1,9 @@
 use thiserror::Error;
 
+use crate::ast::Node;
+
+use super::Data;
+
 #[derive(Error, Debug, Clone)]
 pub enum LispError {
     #[error("Type error: {0}")]
@@ -12,6 +16,81 @@ pub enum LispError {
     VariableNotFound(String),
     #[error("Runtime error: {0}")]
     Runtime(String),
+    #[error("{error}")]
+    InExpression { expr: Node, error: Box<LispError> },
+    #[error("{error}\n --> {line}:{column}\n  | {snippet}")]
+    WithSource {
+        error: Box<LispError>,
+        snippet: String,
+        line: usize,
+        column: usize,
+    },
+    /// Not a failure: carries the values of a `recur` up to its `loop`.
+    #[error("recur can only be used inside loop.")]
+    Recur(Vec<Data>),
+    /// Not a failure: `(exit code)` asks whoever is running the program to
+    /// stop with the given status.
+    #[error("exit was called with status {0}.")]
+    Exit(i32),
+}
+
+impl LispError {
+    /// Attributes the error to the given subexpression.
+    pub fn in_expression(self, expr: &Node) -> Self {
+        LispError::InExpression {
+            expr: expr.clone(),
+            error: Box::new(self),
+        }
+    }
+
+    /// The error itself, without the expression or source it was attributed
+    /// to.
+    pub fn root(&self) -> &LispError {
+        match self {
+            LispError::InExpression { error, .. } | LispError::WithSource { error, .. } => {
+                error.root()
+            }
+            _ => self,
+        }
+    }
+
+    /// The name `try-catch` clauses use for this kind of error, or `None` for
+    /// `recur` and `exit`, which aren't failures and can't be caught.
+    pub fn kind(&self) -> Option<&'static str> {
+        match self.root() {
+            LispError::TypeError(_) => Some("type-error"),
+            LispError::SyntaxError(_) => Some("syntax-error"),
+            LispError::StackEmpty => Some("stack-empty"),
+            LispError::VariableNotFound(_) => Some("variable-not-found"),
+            LispError::Runtime(_) => Some("runtime-error"),
+            LispError::Recur(_) | LispError::Exit(_) => None,
+            LispError::InExpression { .. } | LispError::WithSource { .. } => unreachable!(),
+        }
+    }
+
+    /// Points the error at the offending subexpression inside `src`, if the
+    /// error knows which one it was. Nodes don't carry spans, so the first
+    /// occurrence of the subexpression's source is used.
+    pub fn with_context(self, src: &str) -> Self {
+        let LispError::InExpression { expr, error } = self else {
+            return self;
+        };
+        let snippet = expr.to_source();
+        match src.find(&snippet) {
+            Some(offset) => {
+                let before = &src[..offset];
+                let line = before.matches('\n').count() + 1;
+                let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+                LispError::WithSource {
+                    error,
+                    snippet,
+                    line,
+                    column,
+                }
+            }
+            None => LispError::InExpression { expr, error },
+        }
+    }
 }
 
 impl From<&LispError> for LispError {
@@ -21,3 +100,20 @@ impl From<&LispError> for LispError {
 }
 
 pub type Result<T> = std::result::Result<T, LispError>;
+
+#[cfg(test)]
+mod test {
+    use crate::runtime::Runtime;
+
+    #[test]
+    fn test_with_context() {
+        let src = "(+ 1\n   \"x\")";
+        let (_, node) = crate::parser::node(src.as_bytes()).unwrap();
+        let mut runtime = Runtime::try_new().unwrap();
+        let err = runtime.eval(node).unwrap_err().with_context(src);
+        assert_eq!(
+            "Type error: \"x\" is not a number.\n --> 2:4\n  | \"x\"",
+            err.to_string()
+        );
+    }
+}
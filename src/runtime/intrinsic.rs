@@ -1,12 +1,61 @@
+use std::{
+    cell::RefCell, cmp::Ordering, collections::HashMap, io::Write, num::IntErrorKind, rc::Rc,
+};
+
 use crate::ast::Node;
 
 use super::{
+    coerce,
     error::{LispError, Result},
-    Data, NSStack,
+    json,
+    list::List,
+    output_error,
+    regex::Regex,
+    ArithMode, Ctx, Data, Lambda, XorShift,
 };
 
-pub fn f_let(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    if args.len() % 2 != 0 {
+/// The forms that decide how their arguments are evaluated. Binding one of
+/// these names would quietly break every later use of the form, so the
+/// binding forms refuse them.
+const SPECIAL_FORMS: &[&str] = &[
+    "let",
+    "quote",
+    "quote-deep",
+    "unquote",
+    "syntax-quote",
+    "comment",
+    "do",
+    "with-scope",
+    "with-redefs",
+    "if",
+    "if-let",
+    "when-let",
+    "match",
+    "try-catch",
+    "loop",
+    "recur",
+    "letrec",
+    "letfn",
+    "fn",
+    "defn",
+    "defmacro",
+];
+
+fn check_bindable(name: &str) -> Result<()> {
+    if SPECIAL_FORMS.contains(&name) {
+        return Err(LispError::SyntaxError(format!(
+            "cannot rebind special form {name}"
+        )));
+    }
+    Ok(())
+}
+
+// Bindings always go into the innermost namespace: re-binding a name that
+// already lives there overwrites it, while binding a name from an outer
+// namespace shadows it until the inner scope is exited. A list of
+// identifiers as the target destructures a list value of the same length.
+pub fn f_let(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if !args.len().is_multiple_of(2) {
         return Err(LispError::SyntaxError(
             "Variable declaration mismatch.".into(),
         ));
@@ -14,8 +63,37 @@ pub fn f_let(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
     for i in args.chunks(2) {
         match &i[0] {
             Node::Identifier(id) => {
-                let param_value = i[1].eval(stack).unwrap();
-                stack.top().unwrap().insert(id.clone(), param_value);
+                check_bindable(id)?;
+                let param_value = i[1].eval(ctx)?;
+                ctx.stack.top()?.insert(id.clone(), param_value);
+            }
+            Node::List(targets) => {
+                for target in targets {
+                    if let Node::Identifier(id) = target {
+                        check_bindable(id)?;
+                    }
+                }
+                let values = list_operand(ctx, &i[1])?;
+                if values.len() != targets.len() {
+                    return Err(LispError::Runtime(format!(
+                        "Can't destructure {} values into {} names.",
+                        values.len(),
+                        targets.len()
+                    )));
+                }
+                for (target, value) in targets.iter().zip(values) {
+                    match target {
+                        Node::Identifier(id) => {
+                            ctx.stack.top()?.insert(id.clone(), value);
+                        }
+                        _ => {
+                            return Err(LispError::TypeError(format!(
+                                "{:?} is not an identifier.",
+                                target
+                            )))
+                        }
+                    }
+                }
             }
             _ => {
                 return Err(LispError::TypeError(format!(
@@ -28,29 +106,545 @@ pub fn f_let(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
     Ok(Data::Empty)
 }
 
-pub fn f_do(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
+// A do block gets its own scope: a let inside it is visible to the later
+// forms of the block, but not after the block ends.
+pub fn f_do(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    ctx.stack.enter_scope();
     let mut ret = Err(LispError::SyntaxError("Empty do block".into()));
     for node in args {
-        ret = node.eval(stack);
-        ret.as_ref()?;
+        ret = node.eval(ctx);
+        if ret.is_err() {
+            break;
+        }
+    }
+    ctx.stack.exit_scope();
+    ret
+}
+
+// (swap! name f args...) sets the innermost binding of name to
+// (f value args...) and returns the new value.
+pub fn swap(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let [Node::Identifier(name), f, rest @ ..] = args else {
+        return Err(LispError::SyntaxError(
+            "swap! should get a name and a function.".into(),
+        ));
+    };
+    check_bindable(name)?;
+    let f = f.eval(ctx)?;
+    let mut call_args = vec![ctx.stack.lookup(name)?.clone()];
+    for node in rest {
+        call_args.push(node.eval(ctx)?);
+    }
+    let value = f.apply(ctx, call_args)?;
+    *ctx.stack.lookup_mut(name)? = value.clone();
+    Ok(value)
+}
+
+// (with-scope body ...) evaluates its body in a fresh namespace that is
+// dropped afterwards. Unlike do, an empty body is allowed.
+pub fn with_scope(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    ctx.stack.enter_scope();
+    let mut ret = Ok(Data::Empty);
+    for node in args {
+        ret = node.eval(ctx);
+        if ret.is_err() {
+            break;
+        }
     }
+    ctx.stack.exit_scope();
     ret
 }
 
-pub fn f_if(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
+// (with-redefs (name value ...) body ...) rebinds global names while the body
+// runs and puts the old bindings back afterwards, even if the body fails.
+// Names that weren't bound before are unbound again. Handy for stubbing out
+// functions in tests.
+pub fn with_redefs(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let Some((Node::List(bindings), body)) = args.split_first() else {
+        return Err(LispError::SyntaxError(
+            "with-redefs needs a list of bindings and a body".into(),
+        ));
+    };
+    if !bindings.len().is_multiple_of(2) {
+        return Err(LispError::SyntaxError(
+            "Variable declaration mismatch.".into(),
+        ));
+    }
+    let mut redefs = Vec::with_capacity(bindings.len() / 2);
+    for pair in bindings.chunks(2) {
+        let Node::Identifier(name) = &pair[0] else {
+            return Err(LispError::SyntaxError(format!(
+                "Can't rebind {}: not an identifier.",
+                pair[0].to_source()
+            )));
+        };
+        check_bindable(name)?;
+        redefs.push((name, pair[1].eval(ctx)?));
+    }
+    // Restored in reverse, so a name given twice ends up with its original.
+    let mut originals = Vec::with_capacity(redefs.len());
+    for (name, value) in redefs {
+        originals.push((name, ctx.stack.replace_global(name, Some(value))?));
+    }
+    let mut r = Ok(Data::Empty);
+    for node in body {
+        r = node.eval(ctx);
+        if r.is_err() {
+            break;
+        }
+    }
+    for (name, original) in originals.into_iter().rev() {
+        ctx.stack.replace_global(name, original)?;
+    }
+    r
+}
+
+pub fn f_if(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
     if args.len() != 3 {
         Err(LispError::SyntaxError(
             "If statement should have 3 arguments.".into(),
         ))
-    } else if args[0].eval(stack)?.is_truthy() {
-        args[1].eval(stack)
+    } else if args[0].eval(ctx)?.is_truthy() {
+        args[1].eval(ctx)
     } else {
-        args[2].eval(stack)
+        args[2].eval(ctx)
+    }
+}
+
+// (loop (name init ...) body) evaluates body with the names bound; a
+// (recur value ...) inside it rebinds them and runs body again, without
+// growing the native stack.
+pub fn f_loop(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (bindings, body) = match args {
+        [Node::List(bindings), body] if bindings.len().is_multiple_of(2) => (bindings, body),
+        _ => {
+            return Err(LispError::SyntaxError(
+                "loop should get a list of name/value pairs and a body.".into(),
+            ))
+        }
+    };
+    let mut names = Vec::with_capacity(bindings.len() / 2);
+    for pair in bindings.chunks(2) {
+        match &pair[0] {
+            Node::Identifier(name) => {
+                check_bindable(name)?;
+                names.push(name.clone());
+            }
+            n => {
+                return Err(LispError::TypeError(format!(
+                    "{:?} is not an identifier.",
+                    n
+                )))
+            }
+        }
+    }
+    check_recur_positions(ctx, body, true)?;
+    ctx.stack.enter_scope();
+    let r = run_loop(ctx, &names, bindings, body);
+    ctx.stack.exit_scope();
+    r
+}
+
+fn run_loop(ctx: &mut Ctx, names: &[String], bindings: &[Node], body: &Node) -> Result<Data> {
+    for (name, pair) in names.iter().zip(bindings.chunks(2)) {
+        let value = pair[1].eval(ctx)?;
+        ctx.stack.top()?.insert(name.clone(), value);
+    }
+    loop {
+        match body.eval(ctx) {
+            Err(LispError::Recur(values)) => {
+                if values.len() != names.len() {
+                    return Err(LispError::SyntaxError(format!(
+                        "recur got {} values for {} loop bindings.",
+                        values.len(),
+                        names.len()
+                    )));
+                }
+                let scope = ctx.stack.top()?;
+                for (name, value) in names.iter().zip(values) {
+                    scope.insert(name.clone(), value);
+                }
+            }
+            r => return r,
+        }
+    }
+}
+
+// A recur only makes sense as the last thing a loop body does: anywhere else
+// the work waiting on its value would be silently dropped. This walks the
+// body through the forms whose tail positions are known and rejects a recur
+// outside of them. Forms it can't see into, such as macros, are left alone,
+// as are nested functions and loops, which recur can't escape anyway.
+fn check_recur_positions(ctx: &Ctx, node: &Node, tail: bool) -> Result<()> {
+    let Node::List(nodes) = node else {
+        return Ok(());
+    };
+    let not_tail = |nodes: &[Node]| {
+        nodes
+            .iter()
+            .try_for_each(|node| check_recur_positions(ctx, node, false))
+    };
+    let Some((Node::Identifier(name), args)) = nodes.split_first() else {
+        return not_tail(nodes);
+    };
+    // Only the last form of a body is in tail position.
+    let body = |nodes: &[Node]| match nodes.split_last() {
+        Some((last, rest)) => {
+            not_tail(rest)?;
+            check_recur_positions(ctx, last, tail)
+        }
+        None => Ok(()),
+    };
+    // The values of a (name value ...) binding list.
+    let values = |bindings: &Node| match bindings {
+        Node::List(bindings) => bindings
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .try_for_each(|node| check_recur_positions(ctx, node, false)),
+        _ => Ok(()),
+    };
+    let clauses = |clauses: &[Node]| {
+        clauses.iter().try_for_each(|clause| match clause {
+            Node::List(parts) if !parts.is_empty() => body(&parts[1..]),
+            _ => Ok(()),
+        })
+    };
+    match (name.as_str(), args) {
+        ("recur", _) if !tail => Err(LispError::SyntaxError(format!(
+            "{} is not the last thing its loop does.",
+            node.to_source()
+        ))),
+        ("recur", _) => not_tail(args),
+        ("if", [condition, branches @ ..]) => {
+            check_recur_positions(ctx, condition, false)?;
+            branches
+                .iter()
+                .try_for_each(|node| check_recur_positions(ctx, node, tail))
+        }
+        ("do" | "with-scope", _) => body(args),
+        ("let", _) => args
+            .iter()
+            .skip(1)
+            .step_by(2)
+            .try_for_each(|node| check_recur_positions(ctx, node, false)),
+        ("if-let", [binding, branches @ ..]) => {
+            values(binding)?;
+            branches
+                .iter()
+                .try_for_each(|node| check_recur_positions(ctx, node, tail))
+        }
+        ("when-let" | "letrec" | "with-redefs", [bindings, rest @ ..]) => {
+            values(bindings)?;
+            body(rest)
+        }
+        ("letfn", [_, rest @ ..]) => body(rest),
+        ("loop", [bindings, ..]) => values(bindings),
+        ("match", [value, arms @ ..]) => {
+            check_recur_positions(ctx, value, false)?;
+            clauses(arms)
+        }
+        // recur passes through try-catch uncaught, so its body is as much
+        // in tail position as its handlers.
+        ("try-catch", [guarded, handlers @ ..]) => {
+            check_recur_positions(ctx, guarded, tail)?;
+            clauses(handlers)
+        }
+        _ => match ctx.stack.lookup(name) {
+            Ok(Data::Function(_)) => not_tail(args),
+            Ok(Data::Intrinsic(name, _))
+                if !SPECIAL_FORMS.contains(&name.as_str())
+                    && !["->", "->>"].contains(&name.as_str()) =>
+            {
+                not_tail(args)
+            }
+            _ => Ok(()),
+        },
+    }
+}
+
+// (letrec (name value ...) body) binds every name to Empty in a new scope
+// before evaluating any value, so the values (typically functions) can refer
+// to each other regardless of order.
+pub fn letrec(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (bindings, body) = match args {
+        [Node::List(bindings), body] if bindings.len().is_multiple_of(2) => (bindings, body),
+        _ => {
+            return Err(LispError::SyntaxError(
+                "letrec should get a list of name/value pairs and a body.".into(),
+            ))
+        }
+    };
+    ctx.stack.enter_scope();
+    let r = run_letrec(ctx, bindings, body);
+    ctx.stack.exit_scope();
+    r
+}
+
+// (letfn ((name (params) body) ...) body) defines local functions that can
+// call each other, like letrec with fn written out for every value.
+pub fn letfn(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (definitions, body) = match args {
+        [Node::List(definitions), body] => (definitions, body),
+        _ => {
+            return Err(LispError::SyntaxError(
+                "letfn should get a list of function definitions and a body.".into(),
+            ))
+        }
+    };
+    let mut bindings = Vec::with_capacity(definitions.len() * 2);
+    for definition in definitions {
+        match definition {
+            Node::List(parts) if parts.len() >= 3 => {
+                let mut lambda = vec![Node::sym("fn")];
+                lambda.extend_from_slice(&parts[1..]);
+                bindings.push(parts[0].clone());
+                bindings.push(Node::List(lambda));
+            }
+            _ => {
+                return Err(LispError::SyntaxError(
+                    "letfn definitions should look like (name (params) body).".into(),
+                ))
+            }
+        }
+    }
+    ctx.stack.enter_scope();
+    let r = run_letrec(ctx, &bindings, body);
+    ctx.stack.exit_scope();
+    r
+}
+
+fn run_letrec(ctx: &mut Ctx, bindings: &[Node], body: &Node) -> Result<Data> {
+    let mut names = Vec::with_capacity(bindings.len() / 2);
+    for pair in bindings.chunks(2) {
+        match &pair[0] {
+            Node::Identifier(name) => {
+                check_bindable(name)?;
+                ctx.stack.top()?.insert(name.clone(), Data::Empty);
+                names.push(name);
+            }
+            n => {
+                return Err(LispError::TypeError(format!(
+                    "{:?} is not an identifier.",
+                    n
+                )))
+            }
+        }
+    }
+    for (name, pair) in names.into_iter().zip(bindings.chunks(2)) {
+        let value = pair[1].eval(ctx)?;
+        ctx.stack.top()?.insert(name.clone(), value);
+    }
+    body.eval(ctx)
+}
+
+// (match value (pattern body) ...) evaluates the body of the first arm whose
+// pattern fits the value. Patterns are literals, true, false and nil, quoted
+// code, _ to match anything, a name to match anything and bind it, and
+// (list pattern ...) for lists of that length. Names bound by a pattern are
+// only visible to its arm's body.
+pub fn f_match(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (value, arms) = args.split_first().ok_or(LispError::SyntaxError(
+        "match needs a value and some arms.".into(),
+    ))?;
+    let value = value.eval(ctx)?;
+    for arm in arms {
+        let (pattern, body) = arm_parts(arm)?;
+        let mut bindings = Vec::new();
+        if match_pattern(pattern, &value, &mut bindings)? {
+            ctx.stack.enter_scope();
+            let r = ctx
+                .stack
+                .top()
+                .map(|scope| scope.extend(bindings))
+                .and_then(|()| body.eval(ctx));
+            ctx.stack.exit_scope();
+            return r;
+        }
+    }
+    Err(LispError::Runtime(format!("No match arm fits {}.", value)))
+}
+
+fn arm_parts(arm: &Node) -> Result<(&Node, &Node)> {
+    match arm {
+        Node::List(parts) if parts.len() == 2 => Ok((&parts[0], &parts[1])),
+        _ => Err(LispError::SyntaxError(format!(
+            "{} is not a (pattern body) match arm.",
+            arm.to_source()
+        ))),
+    }
+}
+
+fn match_pattern(pattern: &Node, value: &Data, bindings: &mut Vec<(String, Data)>) -> Result<bool> {
+    Ok(match pattern {
+        Node::Identifier(name) => match name.as_str() {
+            "_" => true,
+            "true" => *value == Data::Bool(true),
+            "false" => *value == Data::Bool(false),
+            "nil" => *value == Data::Empty,
+            _ => {
+                check_bindable(name)?;
+                bindings.push((name.clone(), value.clone()));
+                true
+            }
+        },
+        Node::List(parts) if parts.first() == Some(&Node::Identifier("list".into())) => {
+            let Data::List(items) = value else {
+                return Ok(false);
+            };
+            if items.len() != parts.len() - 1 {
+                return Ok(false);
+            }
+            for (part, item) in parts[1..].iter().zip(items) {
+                if !match_pattern(part, item, bindings)? {
+                    return Ok(false);
+                }
+            }
+            true
+        }
+        Node::List(_) | Node::Comment(_) => {
+            return Err(LispError::SyntaxError(format!(
+                "{} is not a pattern.",
+                pattern.to_source()
+            )))
+        }
+        Node::StringLiteral(s) => *value == Data::Str(s.clone()),
+        Node::IntegerLiteral(i) => *value == Data::Int(*i),
+        Node::FloatLiteral(f) => *value == Data::Float(*f),
+        Node::BoolLiteral(b) => *value == Data::Bool(*b),
+        Node::Quote(node) => *value == Data::Quote(*node.clone()),
+    })
+}
+
+pub fn recur(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let mut values = Vec::with_capacity(args.len());
+    for node in args {
+        values.push(node.eval(ctx)?);
+    }
+    Err(LispError::Recur(values))
+}
+
+// (try-catch body (kind handler) ...) evaluates body, and if it fails runs the
+// handler of the first clause naming the error's kind: type-error,
+// syntax-error, stack-empty, variable-not-found or runtime-error, or _ for
+// any of them. A clause written (kind name handler) also binds the error
+// message to name while the handler runs. Errors no clause names are passed
+// on, and recur and exit are never caught.
+pub fn try_catch(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (body, clauses) = args.split_first().ok_or(LispError::SyntaxError(
+        "try-catch needs a body and some clauses.".into(),
+    ))?;
+    let clauses = clauses
+        .iter()
+        .map(catch_clause)
+        .collect::<Result<Vec<_>>>()?;
+    let depth = ctx.stack.depth();
+    let error = match body.eval(ctx) {
+        Err(e) if e.kind().is_some() => e,
+        r => return r,
+    };
+    // Scopes the failed body opened aren't necessarily closed again.
+    ctx.stack.unwind_to(depth);
+    let kind = error.kind();
+    let Some((_, name, handler)) = clauses
+        .into_iter()
+        .find(|(pattern, _, _)| *pattern == "_" || Some(*pattern) == kind)
+    else {
+        return Err(error);
+    };
+    let Some(name) = name else {
+        return handler.eval(ctx);
+    };
+    ctx.stack.enter_scope();
+    let message = Data::Str(error.root().to_string());
+    let r = ctx
+        .stack
+        .top()
+        .map(|scope| scope.insert(name.clone(), message))
+        .and_then(|_| handler.eval(ctx));
+    ctx.stack.exit_scope();
+    r
+}
+
+fn catch_clause(clause: &Node) -> Result<(&str, Option<&String>, &Node)> {
+    let bad_clause = || {
+        LispError::SyntaxError(format!(
+            "{} is not a (kind handler) or (kind name handler) clause.",
+            clause.to_source()
+        ))
+    };
+    let Node::List(parts) = clause else {
+        return Err(bad_clause());
+    };
+    match parts.as_slice() {
+        [Node::Identifier(kind), handler] => Ok((kind, None, handler)),
+        [Node::Identifier(kind), Node::Identifier(name), handler] => {
+            check_bindable(name)?;
+            Ok((kind, Some(name), handler))
+        }
+        _ => Err(bad_clause()),
+    }
+}
+
+// (if-let (name expr) then else): `name` is only bound while `then` runs.
+pub fn if_let(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 3 {
+        return Err(LispError::SyntaxError(
+            "if-let should have a binding and 2 branches.".into(),
+        ));
+    }
+    let (name, value) = conditional_binding(&args[0], "if-let")?;
+    let value = value.eval(ctx)?;
+    if !value.is_truthy() {
+        return args[2].eval(ctx);
+    }
+    ctx.stack.enter_scope();
+    ctx.stack.top()?.insert(name.clone(), value);
+    let r = args[1].eval(ctx);
+    ctx.stack.exit_scope();
+    r
+}
+
+pub fn when_let(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let Some((binding, body)) = args.split_first() else {
+        return Err(LispError::SyntaxError(
+            "when-let should have a binding and a body.".into(),
+        ));
+    };
+    let (name, value) = conditional_binding(binding, "when-let")?;
+    let value = value.eval(ctx)?;
+    if !value.is_truthy() {
+        return Ok(Data::Empty);
+    }
+    ctx.stack.enter_scope();
+    ctx.stack.top()?.insert(name.clone(), value);
+    let mut ret = Ok(Data::Empty);
+    for node in body {
+        ret = node.eval(ctx);
+        if ret.is_err() {
+            break;
+        }
+    }
+    ctx.stack.exit_scope();
+    ret
+}
+
+fn conditional_binding<'a>(binding: &'a Node, form: &str) -> Result<(&'a String, &'a Node)> {
+    match binding {
+        Node::List(binding) => match binding.as_slice() {
+            [Node::Identifier(name), value] => check_bindable(name).map(|_| (name, value)),
+            _ => Err(LispError::SyntaxError(format!(
+                "{form} binding should be (name expression)."
+            ))),
+        },
+        _ => Err(LispError::SyntaxError(format!(
+            "{form} binding should be (name expression)."
+        ))),
     }
 }
 
-pub fn f_fn(_stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    let arg = args.get(0).ok_or(LispError::SyntaxError(
+pub fn f_fn(_ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let arg = args.first().ok_or(LispError::SyntaxError(
         "Function declaration should get a list of arguments and a body!".into(),
     ))?;
     match arg {
@@ -58,7 +652,10 @@ pub fn f_fn(_stack: &mut NSStack, args: &[Node]) -> Result<Data> {
             let mut arglist = Vec::new();
             for i in ns {
                 match i {
-                    Node::Identifier(id) => arglist.push(id.clone()),
+                    Node::Identifier(id) => {
+                        check_bindable(id)?;
+                        arglist.push(id.clone());
+                    }
                     _ => {
                         return Err(LispError::SyntaxError(
                             "When declaring function, all arguments should be identifiers.".into(),
@@ -66,10 +663,31 @@ pub fn f_fn(_stack: &mut NSStack, args: &[Node]) -> Result<Data> {
                     }
                 }
             }
-            let body = args.get(1).ok_or(LispError::SyntaxError(
-                "Function declaration doesn't have a body!".into(),
-            ))?;
-            Ok(Data::Function(arglist, body.clone()))
+            let (pre, body) = match &args[1..] {
+                [body] => (Vec::new(), body),
+                [Node::List(pre), body]
+                    if pre.first() == Some(&Node::Identifier("pre".into())) =>
+                {
+                    (pre[1..].to_vec(), body)
+                }
+                [] => {
+                    return Err(LispError::SyntaxError(
+                        "Function declaration doesn't have a body!".into(),
+                    ))
+                }
+                _ => {
+                    return Err(LispError::SyntaxError(
+                        "Function body should be a single expression, optionally preceded by (pre ...).".into(),
+                    ))
+                }
+            };
+            Ok(Data::Function(Rc::new(Lambda {
+                params: arglist,
+                pre,
+                body: body.clone(),
+                doc: None,
+                captured: Vec::new(),
+            })))
         }
         _ => Err(LispError::SyntaxError(
             "Function arguments should be given in a list.".into(),
@@ -77,113 +695,1735 @@ pub fn f_fn(_stack: &mut NSStack, args: &[Node]) -> Result<Data> {
     }
 }
 
-pub fn quote(_stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    let node = args.get(0).ok_or(LispError::SyntaxError(
+// (defn name "doc" (params) body) binds a function like (let name (fn ...)),
+// keeping the optional docstring for doc.
+pub fn defn(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (name, doc, rest) =
+        match args {
+            [Node::Identifier(name), Node::StringLiteral(doc), rest @ ..] => {
+                (name, Some(doc.clone()), rest)
+            }
+            [Node::Identifier(name), rest @ ..] => (name, None, rest),
+            _ => return Err(LispError::SyntaxError(
+                "defn should get a name, an optional docstring, a list of arguments and a body."
+                    .into(),
+            )),
+        };
+    check_bindable(name)?;
+    let Data::Function(lambda) = f_fn(ctx, rest)? else {
+        unreachable!()
+    };
+    let lambda = Lambda {
+        doc,
+        ..Rc::unwrap_or_clone(lambda)
+    };
+    ctx.stack
+        .top()?
+        .insert(name.clone(), Data::Function(Rc::new(lambda)));
+    Ok(Data::Empty)
+}
+
+pub fn doc(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("doc only takes 1 argument".into()));
+    }
+    let lambda = lambda_operand(ctx, &args[0])?;
+    Ok(lambda.doc.clone().map(Data::Str).unwrap_or(Data::Empty))
+}
+
+pub fn fn_params(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "fn-params only takes 1 argument".into(),
+        ));
+    }
+    let lambda = lambda_operand(ctx, &args[0])?;
+    Ok(Data::List(
+        lambda
+            .params
+            .iter()
+            .map(|name| Data::Quote(Node::Identifier(name.clone())))
+            .collect(),
+    ))
+}
+
+// Only the body proper; a (pre ...) form isn't part of it.
+pub fn fn_body(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "fn-body only takes 1 argument".into(),
+        ));
+    }
+    let lambda = lambda_operand(ctx, &args[0])?;
+    Ok(Data::Quote(lambda.body.clone()))
+}
+
+fn lambda_operand(ctx: &mut Ctx, node: &Node) -> Result<Rc<Lambda>> {
+    match node.eval(ctx)? {
+        Data::Function(lambda) | Data::Macro(lambda) => Ok(lambda),
+        d => Err(LispError::TypeError(format!("{:?} is not a function.", d))),
+    }
+}
+
+// (defmacro name (params) body) takes the same parameter list and body as fn.
+pub fn defmacro(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let Some((Node::Identifier(name), rest)) = args.split_first() else {
+        return Err(LispError::SyntaxError(
+            "defmacro should get a name, a list of arguments and a body.".into(),
+        ));
+    };
+    check_bindable(name)?;
+    let Data::Function(lambda) = f_fn(ctx, rest)? else {
+        unreachable!()
+    };
+    ctx.stack.top()?.insert(name.clone(), Data::Macro(lambda));
+    Ok(Data::Empty)
+}
+
+// Expands a macro call once without evaluating the result. Forms that aren't
+// macro calls come back unchanged.
+pub fn macroexpand(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let [form] = args else {
+        return Err(LispError::SyntaxError(
+            "macroexpand only takes 1 argument".into(),
+        ));
+    };
+    if let Node::List(ops) = form {
+        if let Some(Node::Identifier(name)) = ops.first() {
+            if let Ok(Data::Macro(lambda)) = ctx.stack.lookup(name) {
+                let lambda = lambda.clone();
+                return lambda.expand(ctx, &ops[1..]).map(Data::Quote);
+            }
+        }
+    }
+    Ok(Data::Quote(form.clone()))
+}
+
+pub fn quote(_ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let node = args.first().ok_or(LispError::SyntaxError(
         "Quote received zero arguments.".into(),
     ))?;
     Ok(Data::Quote(node.clone()))
 }
 
-pub fn unquote(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    let node = args.get(0).ok_or(LispError::SyntaxError(
-        "Quote received zero arguments.".into(),
+// (quote-deep (1 "a" (x))) is data rather than code: the list (1 "a" ('x)).
+// Like quote, its argument isn't evaluated.
+pub fn quote_deep(_ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "quote-deep only takes 1 argument".into(),
+        ));
+    }
+    Ok(Data::quote_deep(&args[0]))
+}
+
+// The arguments were already parsed, so they have to be well-formed, but they
+// are never evaluated.
+pub fn comment(_ctx: &mut Ctx, _args: &[Node]) -> Result<Data> {
+    Ok(Data::Empty)
+}
+
+// Like quote, except that (unquote expr) forms anywhere inside the quoted
+// list are evaluated and their values spliced in as code.
+pub fn syntax_quote(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let node = args.first().ok_or(LispError::SyntaxError(
+        "Syntax-quote received zero arguments.".into(),
+    ))?;
+    Ok(Data::Quote(fill_unquotes(ctx, node)?))
+}
+
+fn fill_unquotes(ctx: &mut Ctx, node: &Node) -> Result<Node> {
+    match node {
+        Node::List(items) => match items.as_slice() {
+            [Node::Identifier(op), expr] if op == "unquote" => expr.eval(ctx)?.to_node(),
+            _ => items
+                .iter()
+                .map(|item| fill_unquotes(ctx, item))
+                .collect::<Result<_>>()
+                .map(Node::List),
+        },
+        _ => Ok(node.clone()),
+    }
+}
+
+// Strips exactly one level of quoting: the quoted node is evaluated, so a
+// doubly quoted value comes back as the inner quote rather than being forced.
+pub fn unquote(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let node = args.first().ok_or(LispError::SyntaxError(
+        "Unquote received zero arguments.".into(),
     ))?;
-    let data = node.eval(stack)?;
+    let data = node.eval(ctx)?;
     match data {
-        Data::Quote(n) => n.eval(stack),
+        Data::Quote(n) => n.eval(ctx),
         _ => Err(LispError::TypeError(format!("{:?} is not a quote.", &data))),
     }
 }
 
-pub fn debug(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    for node in args {
-        let r = node.eval(stack)?;
-        println!("{:?}", r);
+// Like unquote, except that values other than quotes evaluate to themselves.
+pub fn eval(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("eval only takes 1 argument".into()));
+    }
+    match args[0].eval(ctx)? {
+        Data::Quote(n) => n.eval(ctx),
+        d => Ok(d),
     }
-    Ok(Data::Empty)
 }
 
-pub fn eq(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    if args.len() != 2 {
-        Err(LispError::SyntaxError("= only takes 2 arguments".into()))
-    } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        Ok(Data::Int(if left == right { 1 } else { 0 }))
+// '(a b) becomes a list of the quotes 'a and 'b.
+pub fn quote_to_list(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "quote->list only takes 1 argument".into(),
+        ));
+    }
+    match args[0].eval(ctx)? {
+        Data::Quote(Node::List(nodes)) => {
+            Ok(Data::List(nodes.into_iter().map(Data::Quote).collect()))
+        }
+        d => Err(LispError::TypeError(format!(
+            "{:?} is not a quoted list.",
+            d
+        ))),
     }
 }
 
-pub fn add(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    if args.len() != 2 {
-        Err(LispError::SyntaxError("+ only takes 2 arguments".into()))
-    } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        match (left, right) {
-            (Data::Int(a), Data::Int(b)) => Ok(Data::Int(a + b)),
-            _ => Err(LispError::TypeError("You can only add integers.".into())),
-        }
+// The inverse of quote->list: every item is turned into code, and the code
+// is collected into a quoted list.
+pub fn list_to_quote(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "list->quote only takes 1 argument".into(),
+        ));
     }
+    list_value(ctx, &args[0])
+        .map(Data::List)?
+        .to_node()
+        .map(Data::Quote)
 }
 
-pub fn sub(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    if args.len() != 2 {
-        Err(LispError::SyntaxError("- only takes 2 arguments".into()))
-    } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        match (left, right) {
-            (Data::Int(a), Data::Int(b)) => Ok(Data::Int(a - b)),
-            _ => Err(LispError::TypeError("You can only add integers.".into())),
+// (is-bound? 'name) or (is-bound? "name").
+pub fn is_bound(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "is-bound? only takes 1 argument".into(),
+        ));
+    }
+    let name = match args[0].eval(ctx)? {
+        Data::Quote(Node::Identifier(name)) | Data::Str(name) => name,
+        d => {
+            return Err(LispError::TypeError(format!(
+                "{:?} is not a quoted name or a string.",
+                d
+            )))
         }
+    };
+    Ok(Data::Bool(ctx.stack.lookup(&name).is_ok()))
+}
+
+pub fn debug(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    for node in args {
+        let r = node.eval(ctx)?;
+        writeln!(ctx.output, "{:?}", r).map_err(output_error)?;
     }
+    Ok(Data::Empty)
 }
 
-pub fn mul(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
-    if args.len() != 2 {
-        Err(LispError::SyntaxError("* only takes 2 arguments".into()))
-    } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        match (left, right) {
-            (Data::Int(a), Data::Int(b)) => Ok(Data::Int(a * b)),
-            _ => Err(LispError::TypeError("You can only add integers.".into())),
+pub fn pprint(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let (value, width) = match args {
+        [value] => (value.eval(ctx)?, 2),
+        [value, width] => {
+            let value = value.eval(ctx)?;
+            let w = int_operand(ctx, width)?;
+            let w = usize::try_from(w).map_err(|_| {
+                LispError::Runtime(format!("Can't indent by {w} spaces.")).in_expression(width)
+            })?;
+            (value, w)
+        }
+        _ => {
+            return Err(LispError::SyntaxError(
+                "pprint takes 1 or 2 arguments".into(),
+            ))
+        }
+    };
+    let mut text = String::new();
+    pretty(&mut text, &value, width, 1);
+    writeln!(ctx.output, "{text}").map_err(output_error)?;
+    Ok(Data::Empty)
+}
+
+// Lists that hold other lists get one item per line, indented `width` spaces
+// per level; everything else is printed as Display would.
+fn pretty(text: &mut String, data: &Data, width: usize, level: usize) {
+    match data {
+        Data::List(items) if items.iter().any(|item| matches!(item, Data::List(_))) => {
+            text.push('(');
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    text.push('\n');
+                    text.push_str(&" ".repeat(width * level));
+                }
+                pretty(text, item, width, level + 1);
+            }
+            text.push(')');
         }
+        data => text.push_str(&data.to_string()),
+    }
+}
+
+pub fn flush(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if !args.is_empty() {
+        return Err(LispError::SyntaxError("flush takes no arguments".into()));
+    }
+    ctx.flush()?;
+    Ok(Data::Empty)
+}
+
+// Milliseconds since the Unix epoch don't fit in an Int, so this is a Float;
+// whole milliseconds stay exact for a very long time.
+pub fn time_ms(_ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if !args.is_empty() {
+        return Err(LispError::SyntaxError("time-ms takes no arguments".into()));
+    }
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_err(|e| LispError::Runtime(format!("The clock is set before 1970: {e}")))?;
+    Ok(Data::Float(now.as_millis() as f64))
+}
+
+pub fn sleep(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("sleep only takes 1 argument".into()));
+    }
+    let ms = int_operand(ctx, &args[0])?;
+    let ms = u64::try_from(ms)
+        .map_err(|_| LispError::Runtime(format!("Can't sleep for {ms} milliseconds.")))?;
+    std::thread::sleep(std::time::Duration::from_millis(ms));
+    Ok(Data::Empty)
+}
+
+// (random n) picks an integer in [0, n).
+pub fn random(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "random only takes 1 argument".into(),
+        ));
+    }
+    let n = int_operand(ctx, &args[0])?;
+    if n <= 0 {
+        return Err(LispError::Runtime(format!(
+            "random needs a positive bound, got {n}."
+        )));
+    }
+    Ok(Data::Int((ctx.rng.next() % n as u64) as i32))
+}
+
+pub fn seed(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("seed! only takes 1 argument".into()));
     }
+    let seed = int_operand(ctx, &args[0])?;
+    ctx.rng = XorShift::new(seed as u64);
+    Ok(Data::Empty)
 }
 
-pub fn div(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
+pub fn eq(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
     if args.len() != 2 {
-        Err(LispError::SyntaxError("/ only takes 2 arguments".into()))
+        Err(LispError::SyntaxError("= only takes 2 arguments".into()))
     } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        match (left, right) {
-            (Data::Int(a), Data::Int(b)) => Ok(Data::Int(a / b)),
-            _ => Err(LispError::TypeError("You can only add integers.".into())),
-        }
+        let left = args[0].eval(ctx)?;
+        let right = args[1].eval(ctx)?;
+        Ok(Data::Bool(left == right))
     }
 }
 
-pub fn modul(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
+// (eq? a b) asks whether a and b are the same value, which for a list means
+// the same cells: two lists built separately are never eq?, even with equal
+// items. (equal? a b) compares structurally, item by item, like =.
+pub fn is_eq(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
     if args.len() != 2 {
-        Err(LispError::SyntaxError("mod only takes 2 arguments".into()))
-    } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        match (left, right) {
-            (Data::Int(a), Data::Int(b)) => Ok(Data::Int(a % b)),
-            _ => Err(LispError::TypeError("You can only add integers.".into())),
-        }
+        return Err(LispError::SyntaxError("eq? only takes 2 arguments".into()));
     }
+    let left = args[0].eval(ctx)?;
+    let right = args[1].eval(ctx)?;
+    Ok(Data::Bool(left.is_identical(&right)))
 }
 
-pub fn ne(stack: &mut NSStack, args: &[Node]) -> Result<Data> {
+pub fn is_equal(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
     if args.len() != 2 {
-        Err(LispError::SyntaxError("= only takes 2 arguments".into()))
-    } else {
-        let left = args[0].eval(stack)?;
-        let right = args[1].eval(stack)?;
-        Ok(Data::Int(if left == right { 0 } else { 1 }))
+        return Err(LispError::SyntaxError(
+            "equal? only takes 2 arguments".into(),
+        ));
+    }
+    let left = args[0].eval(ctx)?;
+    let right = args[1].eval(ctx)?;
+    Ok(Data::Bool(left == right))
+}
+
+struct IntOps {
+    checked: fn(i32, i32) -> Option<i32>,
+    wrapping: fn(i32, i32) -> i32,
+    saturating: fn(i32, i32) -> i32,
+    divides: bool,
+}
+
+const ADD: IntOps = IntOps {
+    checked: i32::checked_add,
+    wrapping: i32::wrapping_add,
+    saturating: i32::saturating_add,
+    divides: false,
+};
+
+const SUB: IntOps = IntOps {
+    checked: i32::checked_sub,
+    wrapping: i32::wrapping_sub,
+    saturating: i32::saturating_sub,
+    divides: false,
+};
+
+const MUL: IntOps = IntOps {
+    checked: i32::checked_mul,
+    wrapping: i32::wrapping_mul,
+    saturating: i32::saturating_mul,
+    divides: false,
+};
+
+const DIV: IntOps = IntOps {
+    checked: i32::checked_div,
+    wrapping: i32::wrapping_div,
+    saturating: i32::saturating_div,
+    divides: true,
+};
+
+// The only overflowing remainder is i32::MIN % -1, whose true value is 0.
+const REM: IntOps = IntOps {
+    checked: i32::checked_rem,
+    wrapping: i32::wrapping_rem,
+    saturating: i32::wrapping_rem,
+    divides: true,
+};
+
+fn binary_operands(ctx: &mut Ctx, args: &[Node], name: &str) -> Result<(Data, Data)> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 2 arguments"
+        )));
+    }
+    let left = number_operand(ctx, &args[0])?;
+    let right = number_operand(ctx, &args[1])?;
+    Ok((left, right))
+}
+
+fn number_operand(ctx: &mut Ctx, node: &Node) -> Result<Data> {
+    let d = node.eval(ctx)?;
+    coerce::expect_number(&d, &node.to_source()).map_err(|e| e.in_expression(node))?;
+    Ok(d)
+}
+
+fn int_operand(ctx: &mut Ctx, node: &Node) -> Result<i32> {
+    coerce::expect_int(&node.eval(ctx)?, &node.to_source()).map_err(|e| e.in_expression(node))
+}
+
+fn str_operand(ctx: &mut Ctx, node: &Node) -> Result<String> {
+    coerce::expect_str(&node.eval(ctx)?, &node.to_source())
+        .map(str::to_owned)
+        .map_err(|e| e.in_expression(node))
+}
+
+// Integers follow the runtime's arithmetic mode; as soon as either operand
+// is a float, both are treated as floats.
+fn arith(
+    ctx: &mut Ctx,
+    args: &[Node],
+    name: &str,
+    ops: &IntOps,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<Data> {
+    match binary_operands(ctx, args, name)? {
+        (Data::Int(_), Data::Int(0)) if ops.divides => {
+            Err(LispError::Runtime("Division by zero.".into()))
+        }
+        (Data::Int(a), Data::Int(b)) => apply_arith_mode(ctx.arith_mode, name, a, b, ops),
+        (a, b) => Ok(Data::Float(float_op(
+            coerce::expect_number(&a, name)?,
+            coerce::expect_number(&b, name)?,
+        ))),
+    }
+}
+
+fn apply_arith_mode(mode: ArithMode, name: &str, a: i32, b: i32, ops: &IntOps) -> Result<Data> {
+    match mode {
+        ArithMode::Checked => (ops.checked)(a, b)
+            .map(Data::Int)
+            .ok_or(LispError::Runtime(format!("Integer overflow in {name}."))),
+        ArithMode::Wrapping => Ok(Data::Int((ops.wrapping)(a, b))),
+        ArithMode::Saturating => Ok(Data::Int((ops.saturating)(a, b))),
+    }
+}
+
+pub fn add(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    arith(ctx, args, "+", &ADD, |a, b| a + b)
+}
+
+pub fn sub(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    arith(ctx, args, "-", &SUB, |a, b| a - b)
+}
+
+pub fn mul(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    arith(ctx, args, "*", &MUL, |a, b| a * b)
+}
+
+pub fn div(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    arith(ctx, args, "/", &DIV, |a, b| a / b)
+}
+
+pub fn modul(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    arith(ctx, args, "mod", &REM, |a, b| a % b)
+}
+
+pub fn ne(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        Err(LispError::SyntaxError("= only takes 2 arguments".into()))
+    } else {
+        let left = args[0].eval(ctx)?;
+        let right = args[1].eval(ctx)?;
+        Ok(Data::Bool(left != right))
+    }
+}
+
+pub fn lt(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    compare(ctx, args, "<", Ordering::is_lt)
+}
+
+pub fn gt(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    compare(ctx, args, ">", Ordering::is_gt)
+}
+
+pub fn le(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    compare(ctx, args, "<=", Ordering::is_le)
+}
+
+pub fn ge(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    compare(ctx, args, ">=", Ordering::is_ge)
+}
+
+// Comparisons involving NaN are always false, as in IEEE 754.
+fn compare(ctx: &mut Ctx, args: &[Node], name: &str, accept: fn(Ordering) -> bool) -> Result<Data> {
+    let ordering = match binary_operands(ctx, args, name)? {
+        (Data::Int(a), Data::Int(b)) => Some(a.cmp(&b)),
+        (a, b) => coerce::expect_number(&a, name)?.partial_cmp(&coerce::expect_number(&b, name)?),
+    };
+    Ok(Data::Bool(ordering.is_some_and(accept)))
+}
+
+pub fn is_nan(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("nan? only takes 1 argument".into()));
+    }
+    let n = number_operand(ctx, &args[0])?;
+    Ok(Data::Bool(matches!(n, Data::Float(f) if f.is_nan())))
+}
+
+pub fn to_float(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "to-float only takes 1 argument".into(),
+        ));
+    }
+    let n = args[0].eval(ctx)?;
+    coerce::expect_number(&n, &args[0].to_source())
+        .map(Data::Float)
+        .map_err(|e| e.in_expression(&args[0]))
+}
+
+pub fn to_int(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    float_to_int(ctx, args, "to-int", f64::trunc)
+}
+
+pub fn round_to_int(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    float_to_int(ctx, args, "round-to-int", f64::round)
+}
+
+fn float_to_int(ctx: &mut Ctx, args: &[Node], name: &str, round: fn(f64) -> f64) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    match number_operand(ctx, &args[0])? {
+        Data::Float(f) => {
+            let rounded = round(f);
+            if (i32::MIN as f64..=i32::MAX as f64).contains(&rounded) {
+                Ok(Data::Int(rounded as i32))
+            } else {
+                Err(LispError::Runtime(format!(
+                    "{f} is out of the integer range."
+                )))
+            }
+        }
+        i => Ok(i),
+    }
+}
+
+pub fn str_to_int(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "str->int only takes 1 argument".into(),
+        ));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    s.trim().parse().map(Data::Int).map_err(|e| match e.kind() {
+        IntErrorKind::PosOverflow | IntErrorKind::NegOverflow => {
+            LispError::Runtime("integer out of range".into())
+        }
+        _ => LispError::Runtime(format!("{s:?} is not an integer.")),
+    })
+}
+
+pub fn to_radix(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "to-radix only takes 2 arguments".into(),
+        ));
+    }
+    let n = int_operand(ctx, &args[0])?;
+    let base = int_operand(ctx, &args[1])?;
+    if !(2..=36).contains(&base) {
+        return Err(LispError::Runtime(format!(
+            "Base {base} is outside of 2..=36."
+        )));
+    }
+    // Widened so that the magnitude of i32::MIN fits.
+    let mut magnitude = (n as i64).unsigned_abs();
+    let mut digits = Vec::new();
+    loop {
+        let digit = (magnitude % base as u64) as u32;
+        digits.push(char::from_digit(digit, base as u32).unwrap());
+        magnitude /= base as u64;
+        if magnitude == 0 {
+            break;
+        }
+    }
+    if n < 0 {
+        digits.push('-');
+    }
+    Ok(Data::Str(digits.into_iter().rev().collect()))
+}
+
+pub fn argv(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if !args.is_empty() {
+        return Err(LispError::SyntaxError("argv takes no arguments".into()));
+    }
+    Ok(Data::List(
+        ctx.args.iter().cloned().map(Data::Str).collect(),
+    ))
+}
+
+// The failure message quotes the asserted expression as written, since its
+// value (false or nil) says nothing about what went wrong.
+pub fn assert(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "assert only takes 1 argument".into(),
+        ));
+    }
+    if args[0].eval(ctx)?.is_truthy() {
+        Ok(Data::Empty)
+    } else {
+        Err(LispError::Runtime(format!(
+            "assertion failed: {}",
+            args[0].to_source()
+        )))
+    }
+}
+
+// Unwinds like an error; the runner decides what stopping means.
+pub fn exit(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let code = match args {
+        [] => 0,
+        [code] => int_operand(ctx, code)?,
+        _ => {
+            return Err(LispError::SyntaxError(
+                "exit takes at most 1 argument".into(),
+            ))
+        }
+    };
+    Err(LispError::Exit(code))
+}
+
+pub fn getenv(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "getenv only takes 1 argument".into(),
+        ));
+    }
+    let name = str_operand(ctx, &args[0])?;
+    let value = match ctx.env.get(&name) {
+        Some(value) => Some(value.clone()),
+        None => std::env::var(name).ok(),
+    };
+    Ok(value.map(Data::Str).unwrap_or(Data::Empty))
+}
+
+pub fn setenv(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "setenv only takes 2 arguments".into(),
+        ));
+    }
+    let name = str_operand(ctx, &args[0])?;
+    let value = str_operand(ctx, &args[1])?;
+    if name.is_empty() || name.contains(['=', '\0']) || value.contains('\0') {
+        return Err(LispError::Runtime(format!(
+            "Can't set environment variable {name:?}."
+        )));
+    }
+    ctx.env.insert(name, value);
+    Ok(Data::Empty)
+}
+
+pub fn read_bytes(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "read-bytes only takes 1 argument".into(),
+        ));
+    }
+    match args[0].eval(ctx)? {
+        Data::Str(path) => std::fs::read(&path)
+            .map(Data::Bytes)
+            .map_err(|e| LispError::Runtime(format!("Couldn't read {path}: {e}"))),
+        d => Err(LispError::TypeError(format!("{:?} is not a path.", d))),
+    }
+}
+
+pub fn bytes_len(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "bytes-len only takes 1 argument".into(),
+        ));
+    }
+    match args[0].eval(ctx)? {
+        Data::Bytes(b) => Ok(Data::Int(b.len() as i32)),
+        d => Err(LispError::TypeError(format!("{:?} is not bytes.", d))),
+    }
+}
+
+pub fn byte_at(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "byte-at only takes 2 arguments".into(),
+        ));
+    }
+    let bytes = args[0].eval(ctx)?;
+    let index = args[1].eval(ctx)?;
+    match (bytes, index) {
+        (Data::Bytes(b), Data::Int(i)) => usize::try_from(i)
+            .ok()
+            .and_then(|i| b.get(i))
+            .map(|byte| Data::Int(*byte as i32))
+            .ok_or(LispError::Runtime(format!(
+                "Index {i} is out of range for {} bytes.",
+                b.len()
+            ))),
+        _ => Err(LispError::TypeError(
+            "byte-at takes bytes and an integer index.".into(),
+        )),
+    }
+}
+
+pub fn list(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    ctx.check_collection_size(args.len())?;
+    let mut items = Vec::with_capacity(args.len());
+    for node in args {
+        items.push(node.eval(ctx)?);
+    }
+    Ok(Data::list(items))
+}
+
+pub fn cons(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError("cons only takes 2 arguments".into()));
+    }
+    let head = args[0].eval(ctx)?;
+    let tail = list_value(ctx, &args[1])?;
+    ctx.check_collection_size(tail.len() + 1)?;
+    Ok(Data::List(tail.cons(head)))
+}
+
+pub fn repeat(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "repeat only takes 2 arguments".into(),
+        ));
+    }
+    let n = int_operand(ctx, &args[0])?;
+    let count = usize::try_from(n).map_err(|_| {
+        LispError::Runtime(format!("repeat count {n} is negative")).in_expression(&args[0])
+    })?;
+    ctx.check_collection_size(count)?;
+    let item = args[1].eval(ctx)?;
+    Ok(Data::list(vec![item; count]))
+}
+
+pub fn car(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("car only takes 1 argument".into()));
+    }
+    accessor_operand(ctx, &args[0])?
+        .first()
+        .cloned()
+        .ok_or(LispError::Runtime("car of an empty list.".into()))
+}
+
+pub fn cdr(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("cdr only takes 1 argument".into()));
+    }
+    // The rest of a quoted list stays quoted, so car and cdr can keep
+    // walking it.
+    if let Data::Quote(Node::List(mut nodes)) = args[0].eval(ctx)? {
+        if nodes.is_empty() {
+            return Err(LispError::Runtime("cdr of an empty list.".into()));
+        }
+        nodes.remove(0);
+        return Ok(Data::Quote(Node::List(nodes)));
+    }
+    list_value(ctx, &args[0])?
+        .rest()
+        .map(Data::List)
+        .ok_or(LispError::Runtime("cdr of an empty list.".into()))
+}
+
+pub fn nth(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError("nth only takes 2 arguments".into()));
+    }
+    let items = accessor_operand(ctx, &args[0])?;
+    let index = int_operand(ctx, &args[1])?;
+    let len = items.len();
+    usize::try_from(index)
+        .ok()
+        .and_then(|i| items.iter().nth(i).cloned())
+        .ok_or(LispError::Runtime(format!(
+            "nth {index} of a list with {len} items."
+        )))
+}
+
+pub fn first(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    nth_item(ctx, args, "first", 0)
+}
+
+pub fn second(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    nth_item(ctx, args, "second", 1)
+}
+
+pub fn third(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    nth_item(ctx, args, "third", 2)
+}
+
+fn nth_item(ctx: &mut Ctx, args: &[Node], name: &str, index: usize) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    let items = accessor_operand(ctx, &args[0])?;
+    let len = items.len();
+    items
+        .iter()
+        .nth(index)
+        .cloned()
+        .ok_or(LispError::Runtime(format!(
+            "{name} of a list with {len} items."
+        )))
+}
+
+pub fn last(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("last only takes 1 argument".into()));
+    }
+    accessor_operand(ctx, &args[0])?
+        .iter()
+        .last()
+        .cloned()
+        .ok_or(LispError::Runtime("last of an empty list.".into()))
+}
+
+// Like list_operand, but a quoted list is taken apart the way quote->list
+// does it, so its items come back quoted: (car '(1 2)) is '1, not 1.
+fn accessor_operand(ctx: &mut Ctx, node: &Node) -> Result<List> {
+    match node.eval(ctx)? {
+        Data::Quote(Node::List(nodes)) => Ok(nodes.into_iter().map(Data::Quote).collect()),
+        d => coerce::expect_list(&d, &node.to_source())
+            .cloned()
+            .map_err(|e| e.in_expression(node)),
+    }
+}
+
+pub fn remove(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "remove only takes 2 arguments".into(),
+        ));
+    }
+    let items = list_value(ctx, &args[0])?;
+    let unwanted = args[1].eval(ctx)?;
+    Ok(Data::List(
+        items
+            .iter()
+            .filter(|item| **item != unwanted)
+            .cloned()
+            .collect(),
+    ))
+}
+
+// (distinct lst) keeps the first of each group of equal items. Items are
+// bucketed by hash; quoted code can't be hashed, so it's compared against
+// every other unhashable item instead.
+pub fn distinct(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "distinct only takes 1 argument".into(),
+        ));
+    }
+    let items = list_value(ctx, &args[0])?;
+    let mut kept: Vec<Data> = Vec::new();
+    let mut buckets: HashMap<u64, Vec<usize>> = HashMap::new();
+    let mut unhashable: Vec<usize> = Vec::new();
+    for item in &items {
+        let seen = match item.hash_value() {
+            Some(hash) => buckets.entry(hash).or_default(),
+            None => &mut unhashable,
+        };
+        if !seen.iter().any(|&i| kept[i] == *item) {
+            seen.push(kept.len());
+            kept.push(item.clone());
+        }
+    }
+    Ok(Data::list(kept))
+}
+
+// There is no map type, so maps are association lists: lists of (key value)
+// entries, searched front to back.
+pub fn dissoc(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "dissoc only takes 2 arguments".into(),
+        ));
+    }
+    let entries = list_value(ctx, &args[0])?;
+    let key = args[1].eval(ctx)?;
+    let mut kept = Vec::with_capacity(entries.len());
+    for entry in &entries {
+        if entry_parts(entry)?.0 != &key {
+            kept.push(entry.clone());
+        }
+    }
+    Ok(Data::list(kept))
+}
+
+// (map-get m key default) is the value of the first entry for key, or default
+// if there is none. Without a default a missing key gives nil, which can't be
+// told apart from a key mapped to nil; map-has? can.
+pub fn map_get(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if !(2..=3).contains(&args.len()) {
+        return Err(LispError::SyntaxError(
+            "map-get takes 2 or 3 arguments".into(),
+        ));
+    }
+    let entries = list_value(ctx, &args[0])?;
+    let key = args[1].eval(ctx)?;
+    match find_entry(&entries, &key)? {
+        Some(value) => Ok(value.clone()),
+        None => args
+            .get(2)
+            .map_or(Ok(Data::Empty), |default| default.eval(ctx)),
+    }
+}
+
+pub fn map_has(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "map-has? only takes 2 arguments".into(),
+        ));
+    }
+    let entries = list_value(ctx, &args[0])?;
+    let key = args[1].eval(ctx)?;
+    Ok(Data::Bool(find_entry(&entries, &key)?.is_some()))
+}
+
+// The value of the first entry for key.
+fn find_entry<'a>(entries: &'a List, key: &Data) -> Result<Option<&'a Data>> {
+    for entry in entries {
+        let (entry_key, value) = entry_parts(entry)?;
+        if entry_key == key {
+            return Ok(Some(value));
+        }
+    }
+    Ok(None)
+}
+
+fn entry_parts(entry: &Data) -> Result<(&Data, &Data)> {
+    match entry {
+        Data::List(items) if items.len() == 2 => {
+            let mut parts = items.iter();
+            Ok((parts.next().unwrap(), parts.next().unwrap()))
+        }
+        d => Err(LispError::TypeError(format!(
+            "{:?} is not a (key value) entry.",
+            d
+        ))),
+    }
+}
+
+pub fn is_empty(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "empty? only takes 1 argument".into(),
+        ));
+    }
+    Ok(Data::Bool(list_value(ctx, &args[0])?.is_empty()))
+}
+
+pub fn size_of(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "size-of only takes 1 argument".into(),
+        ));
+    }
+    let size = args[0].eval(ctx)?.approx_size();
+    Ok(Data::Int(i32::try_from(size).unwrap_or(i32::MAX)))
+}
+
+pub fn type_of(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "type-of only takes 1 argument".into(),
+        ));
+    }
+    Ok(Data::Str(args[0].eval(ctx)?.type_name().into()))
+}
+
+pub fn len(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("len only takes 1 argument".into()));
+    }
+    let len = match args[0].eval(ctx)? {
+        Data::List(items) => items.len(),
+        Data::Str(s) => s.chars().count(),
+        Data::Bytes(b) => b.len(),
+        d => {
+            return Err(LispError::TypeError(format!(
+                "{:?} doesn't have a length.",
+                d
+            )))
+        }
+    };
+    Ok(Data::Int(len as i32))
+}
+
+pub fn ok(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("ok only takes 1 argument".into()));
+    }
+    Ok(Data::Ok(Box::new(args[0].eval(ctx)?)))
+}
+
+pub fn err(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("err only takes 1 argument".into()));
+    }
+    Ok(Data::Err(Box::new(args[0].eval(ctx)?)))
+}
+
+pub fn is_true(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    predicate(ctx, args, "true?", |d| matches!(d, Data::Bool(true)))
+}
+
+pub fn is_false(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    predicate(ctx, args, "false?", |d| matches!(d, Data::Bool(false)))
+}
+
+// What if and the other conditionals go by: everything but false and nil.
+pub fn is_truthy(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    predicate(ctx, args, "truthy?", Data::is_truthy)
+}
+
+fn predicate(ctx: &mut Ctx, args: &[Node], name: &str, test: fn(&Data) -> bool) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    Ok(Data::Bool(test(&args[0].eval(ctx)?)))
+}
+
+pub fn is_ok(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("ok? only takes 1 argument".into()));
+    }
+    Ok(Data::Bool(matches!(args[0].eval(ctx)?, Data::Ok(_))))
+}
+
+pub fn is_err(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("err? only takes 1 argument".into()));
+    }
+    Ok(Data::Bool(matches!(args[0].eval(ctx)?, Data::Err(_))))
+}
+
+pub fn unwrap(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "unwrap only takes 1 argument".into(),
+        ));
+    }
+    match args[0].eval(ctx)? {
+        Data::Ok(value) => Ok(*value),
+        Data::Err(value) => Err(LispError::Runtime(format!(
+            "Unwrapped an error: {:?}",
+            value
+        ))),
+        d => Err(LispError::TypeError(format!("{:?} is not a result.", d))),
+    }
+}
+
+pub fn str_builder(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let initial = match args {
+        [] => String::new(),
+        [s] => str_operand(ctx, s)?,
+        _ => {
+            return Err(LispError::SyntaxError(
+                "str-builder takes at most 1 argument".into(),
+            ))
+        }
+    };
+    Ok(Data::StrBuilder(Rc::new(RefCell::new(initial))))
+}
+
+// (append! builder s) appends s in place, so every copy of builder sees it.
+pub fn append(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "append! only takes 2 arguments".into(),
+        ));
+    }
+    let builder = builder_operand(ctx, &args[0])?;
+    let s = str_operand(ctx, &args[1])?;
+    ctx.check_collection_size(builder.borrow().chars().count() + s.chars().count())?;
+    builder.borrow_mut().push_str(&s);
+    Ok(Data::Empty)
+}
+
+pub fn builder_to_str(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "builder->str only takes 1 argument".into(),
+        ));
+    }
+    let builder = builder_operand(ctx, &args[0])?;
+    let s = builder.borrow().clone();
+    Ok(Data::Str(s))
+}
+
+fn builder_operand(ctx: &mut Ctx, node: &Node) -> Result<Rc<RefCell<String>>> {
+    match node.eval(ctx)? {
+        Data::StrBuilder(b) => Ok(b),
+        _ => Err(
+            LispError::TypeError(format!("{} is not a string builder.", node.to_source()))
+                .in_expression(node),
+        ),
+    }
+}
+
+pub fn deep_copy(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "deep-copy only takes 1 argument".into(),
+        ));
+    }
+    Ok(args[0].eval(ctx)?.deep_copy())
+}
+
+pub fn to_json(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "to-json only takes 1 argument".into(),
+        ));
+    }
+    json::to_json(&args[0].eval(ctx)?).map(Data::Str)
+}
+
+pub fn from_json(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "from-json only takes 1 argument".into(),
+        ));
+    }
+    match args[0].eval(ctx)? {
+        Data::Str(s) => json::from_json(&s),
+        d => Err(LispError::TypeError(format!("{:?} is not a string.", d))),
+    }
+}
+
+// (-> x (f a) g) is rewritten to (g (f x a)) before being evaluated.
+pub fn thread_first(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    thread(ctx, args, "->", false)
+}
+
+// (->> x (f a) g) is rewritten to (g (f a x)) before being evaluated.
+pub fn thread_last(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    thread(ctx, args, "->>", true)
+}
+
+fn thread(ctx: &mut Ctx, args: &[Node], name: &str, last: bool) -> Result<Data> {
+    let (first, steps) = args.split_first().ok_or(LispError::SyntaxError(format!(
+        "{name} needs a value to thread."
+    )))?;
+    let mut threaded = first.clone();
+    for step in steps {
+        threaded = match step {
+            Node::List(ops) if !ops.is_empty() => {
+                let mut call = ops.clone();
+                if last {
+                    call.push(threaded);
+                } else {
+                    call.insert(1, threaded);
+                }
+                Node::List(call)
+            }
+            _ => Node::List(vec![step.clone(), threaded]),
+        };
+    }
+    threaded.eval(ctx)
+}
+
+pub fn inc(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    step_by(ctx, args, "inc", 1)
+}
+
+pub fn dec(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    step_by(ctx, args, "dec", -1)
+}
+
+fn step_by(ctx: &mut Ctx, args: &[Node], name: &str, by: i32) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    let n = int_operand(ctx, &args[0])?;
+    apply_arith_mode(ctx.arith_mode, name, n, by, &ADD)
+}
+
+// The bit counts look at the 32-bit two's complement representation, so
+// (popcount -1) is 32.
+pub fn popcount(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    int_unary(ctx, args, "popcount", |n| Data::Int(n.count_ones() as i32))
+}
+
+pub fn leading_zeros(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    int_unary(ctx, args, "leading-zeros", |n| {
+        Data::Int(n.leading_zeros() as i32)
+    })
+}
+
+pub fn trailing_zeros(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    int_unary(ctx, args, "trailing-zeros", |n| {
+        Data::Int(n.trailing_zeros() as i32)
+    })
+}
+
+pub fn is_even(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    int_unary(ctx, args, "even?", |n| Data::Bool(n % 2 == 0))
+}
+
+pub fn is_odd(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    int_unary(ctx, args, "odd?", |n| Data::Bool(n % 2 != 0))
+}
+
+fn int_unary(ctx: &mut Ctx, args: &[Node], name: &str, f: fn(i32) -> Data) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    Ok(f(int_operand(ctx, &args[0])?))
+}
+
+pub fn map(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError("map only takes 2 arguments".into()));
+    }
+    let f = args[0].eval(ctx)?;
+    let items = list_operand(ctx, &args[1])?;
+    let mut mapped = Vec::with_capacity(items.len());
+    for item in items {
+        mapped.push(f.apply(ctx, vec![item])?);
+    }
+    Ok(Data::list(mapped))
+}
+
+// (compose f g h) is (fn (x) (f (g (h x)))). The functions are captured the
+// way partial application captures arguments, under names no identifier can
+// spell.
+pub fn compose(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.is_empty() {
+        return Err(LispError::SyntaxError(
+            "compose needs at least 1 function".into(),
+        ));
+    }
+    let mut captured = Vec::with_capacity(args.len());
+    let mut body = Node::Identifier(" x".into());
+    for (i, node) in args.iter().enumerate().rev() {
+        let f = node.eval(ctx)?;
+        if !matches!(f, Data::Function(_) | Data::Intrinsic(..)) {
+            return Err(
+                LispError::TypeError(format!("{:?} is not a function.", f)).in_expression(node)
+            );
+        }
+        let name = format!(" f{i}");
+        body = Node::List(vec![Node::Identifier(name.clone()), body]);
+        captured.push((name, f));
+    }
+    Ok(Data::Function(Rc::new(Lambda {
+        params: vec![" x".into()],
+        pre: Vec::new(),
+        body,
+        doc: None,
+        captured,
+    })))
+}
+
+pub fn reduce(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 3 {
+        return Err(LispError::SyntaxError(
+            "reduce only takes 3 arguments".into(),
+        ));
+    }
+    let f = args[0].eval(ctx)?;
+    let mut acc = args[1].eval(ctx)?;
+    for item in list_operand(ctx, &args[2])? {
+        acc = f.apply(ctx, vec![acc, item])?;
+    }
+    Ok(acc)
+}
+
+// (fold-right f init (list a b)) is (f a (f b init)): items come first and
+// the accumulator second, the other way around from reduce.
+pub fn fold_right(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 3 {
+        return Err(LispError::SyntaxError(
+            "fold-right only takes 3 arguments".into(),
+        ));
+    }
+    let f = args[0].eval(ctx)?;
+    let mut acc = args[1].eval(ctx)?;
+    for item in list_operand(ctx, &args[2])?.into_iter().rev() {
+        acc = f.apply(ctx, vec![item, acc])?;
+    }
+    Ok(acc)
+}
+
+pub fn sum(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    fold_numbers(ctx, args, "sum", 0, &ADD, |a, b| a + b)
+}
+
+pub fn product(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    fold_numbers(ctx, args, "product", 1, &MUL, |a, b| a * b)
+}
+
+// Folds a list of numbers starting from the identity, with the same int and
+// float rules as the binary operators.
+fn fold_numbers(
+    ctx: &mut Ctx,
+    args: &[Node],
+    name: &str,
+    identity: i32,
+    ops: &IntOps,
+    float_op: fn(f64, f64) -> f64,
+) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    let mut acc = Data::Int(identity);
+    for item in list_operand(ctx, &args[0])? {
+        let x = coerce::expect_number(&item, &item.to_string())?;
+        acc = match (acc, item) {
+            (Data::Int(a), Data::Int(b)) => apply_arith_mode(ctx.arith_mode, name, a, b, ops)?,
+            (a, _) => Data::Float(float_op(coerce::expect_number(&a, name)?, x)),
+        };
+    }
+    Ok(acc)
+}
+
+pub fn mean(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let xs = float_list(ctx, args, "mean")?;
+    Ok(Data::Float(xs.iter().sum::<f64>() / xs.len() as f64))
+}
+
+pub fn median(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let mut xs = float_list(ctx, args, "median")?;
+    xs.sort_by(f64::total_cmp);
+    let mid = xs.len() / 2;
+    Ok(Data::Float(if xs.len().is_multiple_of(2) {
+        (xs[mid - 1] + xs[mid]) / 2.0
+    } else {
+        xs[mid]
+    }))
+}
+
+// The population standard deviation.
+pub fn stddev(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let xs = float_list(ctx, args, "stddev")?;
+    let n = xs.len() as f64;
+    let mean = xs.iter().sum::<f64>() / n;
+    let variance = xs.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n;
+    Ok(Data::Float(variance.sqrt()))
+}
+
+// The single argument of a statistics intrinsic: a non-empty list of numbers.
+fn float_list(ctx: &mut Ctx, args: &[Node], name: &str) -> Result<Vec<f64>> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    let items = list_operand(ctx, &args[0])?;
+    if items.is_empty() {
+        return Err(LispError::Runtime(format!("{name} of an empty list.")));
+    }
+    items
+        .iter()
+        .map(|item| coerce::expect_number(item, &item.to_string()))
+        .collect()
+}
+
+pub fn count_if(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "count-if only takes 2 arguments".into(),
+        ));
+    }
+    let pred = args[0].eval(ctx)?;
+    let mut count = 0;
+    for item in list_operand(ctx, &args[1])? {
+        if pred.apply(ctx, vec![item])?.is_truthy() {
+            count += 1;
+        }
+    }
+    Ok(Data::Int(count))
+}
+
+// (partition pred lst) is (list matching rest): the items pred holds for and
+// the others, both in their original order.
+pub fn partition(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "partition only takes 2 arguments".into(),
+        ));
+    }
+    let pred = args[0].eval(ctx)?;
+    let (mut matching, mut rest) = (Vec::new(), Vec::new());
+    for item in list_operand(ctx, &args[1])? {
+        if pred.apply(ctx, vec![item.clone()])?.is_truthy() {
+            matching.push(item);
+        } else {
+            rest.push(item);
+        }
+    }
+    Ok(Data::list(vec![Data::list(matching), Data::list(rest)]))
+}
+
+pub fn zip(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError("zip only takes 2 arguments".into()));
+    }
+    let left = list_operand(ctx, &args[0])?;
+    let right = list_operand(ctx, &args[1])?;
+    Ok(Data::List(
+        left.into_iter()
+            .zip(right)
+            .map(|(a, b)| Data::list(vec![a, b]))
+            .collect(),
+    ))
+}
+
+pub fn zip_with(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 3 {
+        return Err(LispError::SyntaxError(
+            "zip-with only takes 3 arguments".into(),
+        ));
+    }
+    let f = args[0].eval(ctx)?;
+    let left = list_operand(ctx, &args[1])?;
+    let right = list_operand(ctx, &args[2])?;
+    let mut zipped = Vec::with_capacity(left.len().min(right.len()));
+    for (a, b) in left.into_iter().zip(right) {
+        zipped.push(f.apply(ctx, vec![a, b])?);
+    }
+    Ok(Data::list(zipped))
+}
+
+pub fn flatten(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    flatten_list(ctx, args, "flatten", None)
+}
+
+pub fn flatten_1(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    flatten_list(ctx, args, "flatten-1", Some(1))
+}
+
+fn flatten_list(ctx: &mut Ctx, args: &[Node], name: &str, depth: Option<usize>) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(format!(
+            "{name} only takes 1 argument"
+        )));
+    }
+    let mut flat = Vec::new();
+    flatten_into(&mut flat, list_operand(ctx, &args[0])?, depth);
+    ctx.check_collection_size(flat.len())?;
+    Ok(Data::list(flat))
+}
+
+// Splices nested lists into `flat`, at most `depth` levels deep if given.
+fn flatten_into(flat: &mut Vec<Data>, items: impl IntoIterator<Item = Data>, depth: Option<usize>) {
+    for item in items {
+        match item {
+            Data::List(inner) if depth != Some(0) => {
+                flatten_into(flat, inner, depth.map(|d| d - 1))
+            }
+            item => flat.push(item),
+        }
+    }
+}
+
+fn list_operand(ctx: &mut Ctx, node: &Node) -> Result<Vec<Data>> {
+    list_value(ctx, node).map(|list| list.to_vec())
+}
+
+// Lists are shared rather than copied, so this is cheap where list_operand
+// copies the items out.
+fn list_value(ctx: &mut Ctx, node: &Node) -> Result<List> {
+    coerce::expect_list(&node.eval(ctx)?, &node.to_source())
+        .cloned()
+        .map_err(|e| e.in_expression(node))
+}
+
+// There is no character type, so characters are strings of length one.
+pub fn chars(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("chars only takes 1 argument".into()));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    ctx.check_collection_size(s.chars().count())?;
+    Ok(Data::List(
+        s.chars().map(|c| Data::Str(c.to_string())).collect(),
+    ))
+}
+
+pub fn implode(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError(
+            "implode only takes 1 argument".into(),
+        ));
+    }
+    let mut s = String::new();
+    for item in list_operand(ctx, &args[0])? {
+        s.push_str(coerce::expect_str(&item, &item.to_string())?);
+    }
+    Ok(Data::Str(s))
+}
+
+pub fn upper(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("upper only takes 1 argument".into()));
+    }
+    Ok(Data::Str(str_operand(ctx, &args[0])?.to_uppercase()))
+}
+
+pub fn lower(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("lower only takes 1 argument".into()));
+    }
+    Ok(Data::Str(str_operand(ctx, &args[0])?.to_lowercase()))
+}
+
+pub fn str_eq_ci(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "str-eq-ci only takes 2 arguments".into(),
+        ));
+    }
+    let left = str_operand(ctx, &args[0])?;
+    let right = str_operand(ctx, &args[1])?;
+    Ok(Data::Bool(left.to_lowercase() == right.to_lowercase()))
+}
+
+pub fn trim(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 1 {
+        return Err(LispError::SyntaxError("trim only takes 1 argument".into()));
+    }
+    Ok(Data::Str(str_operand(ctx, &args[0])?.trim().into()))
+}
+
+pub fn starts_with(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "starts-with? only takes 2 arguments".into(),
+        ));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    let prefix = str_operand(ctx, &args[1])?;
+    Ok(Data::Bool(s.starts_with(&prefix)))
+}
+
+// (index-of s needle) counts in chars, like len, not bytes. It's -1 when the
+// needle isn't there.
+pub fn index_of(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "index-of only takes 2 arguments".into(),
+        ));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    let needle = str_operand(ctx, &args[1])?;
+    let index = s
+        .find(&needle)
+        .map_or(-1, |byte| s[..byte].chars().count() as i32);
+    Ok(Data::Int(index))
+}
+
+pub fn string_contains(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "string-contains only takes 2 arguments".into(),
+        ));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    let needle = str_operand(ctx, &args[1])?;
+    Ok(Data::Bool(s.contains(&needle)))
+}
+
+pub fn ends_with(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "ends-with? only takes 2 arguments".into(),
+        ));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    let suffix = str_operand(ctx, &args[1])?;
+    Ok(Data::Bool(s.ends_with(&suffix)))
+}
+
+pub fn replace(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 3 {
+        return Err(LispError::SyntaxError(
+            "replace only takes 3 arguments".into(),
+        ));
+    }
+    let s = str_operand(ctx, &args[0])?;
+    let from = str_operand(ctx, &args[1])?;
+    let to = str_operand(ctx, &args[2])?;
+    if from.is_empty() {
+        return Err(LispError::Runtime(
+            "replace can't search for an empty string.".into(),
+        ));
+    }
+    Ok(Data::Str(s.replace(&from, &to)))
+}
+
+// (format template args...) fills `{}` placeholders with the arguments in
+// order, or `{N}` ones with the Nth argument, which may be used any number
+// of times. Strings are inserted without quotes. `{{` and `}}` are literal
+// braces.
+pub fn format(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    let Some((template, rest)) = args.split_first() else {
+        return Err(LispError::SyntaxError(
+            "format takes a template and its arguments".into(),
+        ));
+    };
+    let template = str_operand(ctx, template)?;
+    let values = rest
+        .iter()
+        .map(|node| node.eval(ctx))
+        .collect::<Result<Vec<_>>>()?;
+    fill_template(&template, &values).map(Data::Str)
+}
+
+fn fill_template(template: &str, values: &[Data]) -> Result<String> {
+    let error = |msg: String| LispError::Runtime(format!("format: {msg}."));
+    let mut out = String::new();
+    let mut next = 0;
+    let (mut sequential, mut positional) = (false, false);
+    let mut chars = template.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.clone().next() == Some('{') => {
+                chars.next();
+                out.push('{');
+            }
+            '}' if chars.clone().next() == Some('}') => {
+                chars.next();
+                out.push('}');
+            }
+            '{' => {
+                let rest = chars.as_str();
+                let Some(close) = rest.find('}') else {
+                    return Err(error("unclosed {".into()));
+                };
+                let spec = &rest[..close];
+                chars = rest[close + 1..].chars();
+                let index = if spec.is_empty() {
+                    sequential = true;
+                    next += 1;
+                    next - 1
+                } else {
+                    positional = true;
+                    spec.parse::<usize>()
+                        .map_err(|_| error(format!("bad placeholder {{{spec}")))?
+                };
+                if sequential && positional {
+                    return Err(error("can't mix {} and {N} placeholders".into()));
+                }
+                let value = values
+                    .get(index)
+                    .ok_or_else(|| error(format!("no argument for placeholder {index}")))?;
+                match value {
+                    Data::Str(s) => out.push_str(s),
+                    other => out.push_str(&other.to_string()),
+                }
+            }
+            '}' => return Err(error("unmatched }".into())),
+            c => out.push(c),
+        }
+    }
+    Ok(out)
+}
+
+pub fn regex_match(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 2 {
+        return Err(LispError::SyntaxError(
+            "regex-match only takes 2 arguments".into(),
+        ));
+    }
+    let regex = Regex::new(&str_operand(ctx, &args[0])?)?;
+    let s = str_operand(ctx, &args[1])?;
+    Ok(regex
+        .find(&s)
+        .map(|(start, end)| Data::Str(s[start..end].to_owned()))
+        .unwrap_or(Data::Empty))
+}
+
+pub fn regex_replace(ctx: &mut Ctx, args: &[Node]) -> Result<Data> {
+    if args.len() != 3 {
+        return Err(LispError::SyntaxError(
+            "regex-replace only takes 3 arguments".into(),
+        ));
     }
+    let regex = Regex::new(&str_operand(ctx, &args[0])?)?;
+    let replacement = str_operand(ctx, &args[1])?;
+    let s = str_operand(ctx, &args[2])?;
+    Ok(Data::Str(regex.replace_all(&s, &replacement)))
 }
@@ -0,0 +1,257 @@
+use nom::{
+    branch::alt,
+    bytes::complete::{tag, take},
+    character::complete::{char, digit1, multispace0, none_of, one_of},
+    combinator::{all_consuming, map, map_res, opt, recognize, value},
+    error::ErrorKind,
+    multi::separated_list0,
+    sequence::{delimited, pair, preceded, terminated, tuple},
+    IResult,
+};
+
+use super::{
+    error::{LispError, Result},
+    list::List,
+    Data,
+};
+
+pub fn to_json(data: &Data) -> Result<String> {
+    let mut out = String::new();
+    write_json(data, &mut out)?;
+    Ok(out)
+}
+
+fn write_json(data: &Data, out: &mut String) -> Result<()> {
+    match data {
+        Data::Bool(b) => out.push_str(&b.to_string()),
+        Data::Int(i) => out.push_str(&i.to_string()),
+        Data::Float(f) if f.is_finite() => out.push_str(&format!("{f:?}")),
+        Data::Str(s) => write_json_string(s, out),
+        Data::Bytes(b) => {
+            let items: Vec<_> = b.iter().map(|byte| Data::Int(*byte as i32)).collect();
+            write_json_array(&items, out)?;
+        }
+        Data::List(items) if is_object(items) => write_json_object(items, out)?,
+        Data::List(items) => write_json_array(items, out)?,
+        Data::Empty => out.push_str("null"),
+        _ => {
+            return Err(LispError::TypeError(format!(
+                "{:?} can't be serialized to JSON.",
+                data
+            )))
+        }
+    }
+    Ok(())
+}
+
+fn write_json_array<'a>(items: impl IntoIterator<Item = &'a Data>, out: &mut String) -> Result<()> {
+    out.push('[');
+    for (i, item) in items.into_iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        write_json(item, out)?;
+    }
+    out.push(']');
+    Ok(())
+}
+
+// A non-empty association list with string keys, the shape from_json reads
+// objects into.
+fn is_object(items: &List) -> bool {
+    !items.is_empty()
+        && items.iter().all(|item| {
+            matches!(item, Data::List(entry)
+                if entry.len() == 2 && matches!(entry.first(), Some(Data::Str(_))))
+        })
+}
+
+fn write_json_object(entries: &List, out: &mut String) -> Result<()> {
+    out.push('{');
+    for (i, entry) in entries.iter().enumerate() {
+        let Data::List(entry) = entry else {
+            unreachable!("checked by is_object")
+        };
+        let mut parts = entry.iter();
+        let (Some(Data::Str(key)), Some(value)) = (parts.next(), parts.next()) else {
+            unreachable!("checked by is_object")
+        };
+        if i > 0 {
+            out.push(',');
+        }
+        write_json_string(key, out);
+        out.push(':');
+        write_json(value, out)?;
+    }
+    out.push('}');
+    Ok(())
+}
+
+fn write_json_string(s: &str, out: &mut String) {
+    out.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+/// How deeply arrays and objects may nest. As with the Lisp parser, this
+/// keeps hostile input from overflowing the native stack. The combinators
+/// take far more stack per level than the parser's hand-written lists, so
+/// the limit is lower; 128 is also what serde_json allows.
+pub const MAX_DEPTH: usize = 128;
+
+// Objects become association lists of (key value) entries, the
+// representation map-get and friends work on, and to_json writes such lists
+// back out as objects. The exception is {}, which comes back as [].
+pub fn from_json(src: &str) -> Result<Data> {
+    match all_consuming(delimited(multispace0, |i| json_value(i, 0), multispace0))(src) {
+        Ok((_, data)) => Ok(data),
+        Err(nom::Err::Failure(e)) if e.code == ErrorKind::MapRes => {
+            Err(LispError::Runtime("integer out of range".into()))
+        }
+        Err(nom::Err::Failure(e)) if e.code == ErrorKind::TooLarge => Err(LispError::Runtime(
+            format!("JSON nested more than {MAX_DEPTH} levels deep"),
+        )),
+        Err(e) => Err(LispError::Runtime(format!("Invalid JSON: {e}"))),
+    }
+}
+
+// depth is how many arrays and objects enclose the value. Dispatching on
+// the first character rather than trying each alternative keeps the stack
+// frames of nested values small.
+fn json_value(input: &str, depth: usize) -> IResult<&str, Data> {
+    match input.chars().next() {
+        Some('[') => json_array(input, depth),
+        Some('{') => json_object(input, depth),
+        Some('"') => map(json_string, Data::Str)(input),
+        _ => alt((
+            value(Data::Empty, tag("null")),
+            value(Data::Bool(true), tag("true")),
+            value(Data::Bool(false), tag("false")),
+            json_number,
+        ))(input),
+    }
+}
+
+// Reads the opening bracket of an array or object, failing outright past
+// the depth limit so that alternatives aren't retried on the way out.
+fn open_bracket(input: &str, bracket: char, depth: usize) -> IResult<&str, char> {
+    let (rest, c) = terminated(char(bracket), multispace0)(input)?;
+    if depth >= MAX_DEPTH {
+        return Err(nom::Err::Failure(nom::error::Error::new(
+            input,
+            ErrorKind::TooLarge,
+        )));
+    }
+    Ok((rest, c))
+}
+
+fn json_number(input: &str) -> IResult<&str, Data> {
+    let (rest, s) = recognize(tuple((
+        opt(char('-')),
+        digit1,
+        opt(pair(char('.'), digit1)),
+        opt(tuple((one_of("eE"), opt(one_of("+-")), digit1))),
+    )))(input)?;
+    let number = if s.contains(['.', 'e', 'E']) {
+        s.parse().map(Data::Float).ok()
+    } else {
+        // The digits are well-formed, so the only way to fail is overflow.
+        match s.parse() {
+            Ok(i) => Some(Data::Int(i)),
+            Err(_) => {
+                return Err(nom::Err::Failure(nom::error::Error::new(
+                    input,
+                    ErrorKind::MapRes,
+                )))
+            }
+        }
+    };
+    match number {
+        Some(n) => Ok((rest, n)),
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            ErrorKind::Digit,
+        ))),
+    }
+}
+
+fn json_array(input: &str, depth: usize) -> IResult<&str, Data> {
+    map(
+        delimited(
+            |i| open_bracket(i, '[', depth),
+            separated_list0(delimited(multispace0, char(','), multispace0), |i| {
+                json_value(i, depth + 1)
+            }),
+            preceded(multispace0, char(']')),
+        ),
+        Data::list,
+    )(input)
+}
+
+fn json_object(input: &str, depth: usize) -> IResult<&str, Data> {
+    let entry = map(
+        tuple((
+            json_string,
+            delimited(multispace0, char(':'), multispace0),
+            |i| json_value(i, depth + 1),
+        )),
+        |(key, _, value)| Data::list(vec![Data::Str(key), value]),
+    );
+    map(
+        delimited(
+            |i| open_bracket(i, '{', depth),
+            separated_list0(delimited(multispace0, char(','), multispace0), entry),
+            preceded(multispace0, char('}')),
+        ),
+        Data::list,
+    )(input)
+}
+
+fn json_string(input: &str) -> IResult<&str, String> {
+    let (mut input, _) = char('"')(input)?;
+    let mut s = String::new();
+    loop {
+        if let Some(rest) = input.strip_prefix('"') {
+            return Ok((rest, s));
+        }
+        let (rest, c) = none_of("\"")(input)?;
+        input = rest;
+        match c {
+            '\\' => {
+                let (rest, escaped) = json_escape(input)?;
+                input = rest;
+                s.push(escaped);
+            }
+            c => s.push(c),
+        }
+    }
+}
+
+fn json_escape(input: &str) -> IResult<&str, char> {
+    alt((
+        value('"', char('"')),
+        value('\\', char('\\')),
+        value('/', char('/')),
+        value('\u{8}', char('b')),
+        value('\u{c}', char('f')),
+        value('\n', char('n')),
+        value('\r', char('r')),
+        value('\t', char('t')),
+        map_res(preceded(char('u'), take(4usize)), |hex: &str| {
+            u32::from_str_radix(hex, 16)
+                .ok()
+                .and_then(char::from_u32)
+                .ok_or(())
+        }),
+    ))(input)
+}
@@ -0,0 +1,201 @@
+use std::{fmt::Debug, rc::Rc};
+
+use super::Data;
+
+/// An immutable singly linked list of values. Lists share structure: `cons`
+/// puts a new cell in front of an existing list without copying it, and
+/// `rest` hands back the tail itself, so both are O(1) and cloning a list
+/// only bumps a reference count.
+#[derive(Clone, Default)]
+pub struct List(Option<Rc<Cell>>);
+
+struct Cell {
+    head: Data,
+    tail: List,
+    len: usize,
+}
+
+impl List {
+    pub fn new() -> Self {
+        Self(None)
+    }
+
+    /// A list with `head` in front of this one, which becomes its tail.
+    pub fn cons(&self, head: Data) -> List {
+        List(Some(Rc::new(Cell {
+            head,
+            tail: self.clone(),
+            len: self.len() + 1,
+        })))
+    }
+
+    pub fn first(&self) -> Option<&Data> {
+        self.0.as_ref().map(|cell| &cell.head)
+    }
+
+    /// Everything after the first item, sharing this list's cells.
+    pub fn rest(&self) -> Option<List> {
+        self.0.as_ref().map(|cell| cell.tail.clone())
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.as_ref().map_or(0, |cell| cell.len)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_none()
+    }
+
+    pub fn iter(&self) -> Iter<'_> {
+        Iter(self)
+    }
+
+    pub fn to_vec(&self) -> Vec<Data> {
+        self.iter().cloned().collect()
+    }
+
+    /// Whether both lists are the very same cells, not just equal items.
+    pub fn ptr_eq(a: &List, b: &List) -> bool {
+        match (&a.0, &b.0) {
+            (Some(a), Some(b)) => Rc::ptr_eq(a, b),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+}
+
+// The default drop would recurse once per cell and overflow the stack on
+// long lists.
+impl Drop for List {
+    fn drop(&mut self) {
+        let mut next = self.0.take();
+        while let Some(cell) = next {
+            match Rc::try_unwrap(cell) {
+                Ok(mut cell) => next = cell.tail.0.take(),
+                Err(_) => break,
+            }
+        }
+    }
+}
+
+impl From<Vec<Data>> for List {
+    fn from(items: Vec<Data>) -> Self {
+        items
+            .into_iter()
+            .rev()
+            .fold(List::new(), |tail, item| tail.cons(item))
+    }
+}
+
+impl FromIterator<Data> for List {
+    fn from_iter<T: IntoIterator<Item = Data>>(iter: T) -> Self {
+        List::from(iter.into_iter().collect::<Vec<_>>())
+    }
+}
+
+impl PartialEq for List {
+    fn eq(&self, other: &Self) -> bool {
+        self.len() == other.len() && self.iter().eq(other.iter())
+    }
+}
+
+impl Debug for List {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.iter()).finish()
+    }
+}
+
+pub struct Iter<'a>(&'a List);
+
+impl<'a> Iterator for Iter<'a> {
+    type Item = &'a Data;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cell = self.0 .0.as_ref()?;
+        self.0 = &cell.tail;
+        Some(&cell.head)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl ExactSizeIterator for Iter<'_> {}
+
+impl<'a> IntoIterator for &'a List {
+    type Item = &'a Data;
+    type IntoIter = Iter<'a>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+/// Moves items out of cells nothing else refers to, and clones the rest.
+pub struct IntoIter(List);
+
+impl Iterator for IntoIter {
+    type Item = Data;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let cell = self.0 .0.take()?;
+        Some(match Rc::try_unwrap(cell) {
+            Ok(mut cell) => {
+                self.0 = std::mem::take(&mut cell.tail);
+                cell.head
+            }
+            Err(cell) => {
+                self.0 = cell.tail.clone();
+                cell.head.clone()
+            }
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.0.len(), Some(self.0.len()))
+    }
+}
+
+impl ExactSizeIterator for IntoIter {}
+
+impl IntoIterator for List {
+    type Item = Data;
+    type IntoIter = IntoIter;
+
+    fn into_iter(self) -> Self::IntoIter {
+        IntoIter(self)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::List;
+    use crate::runtime::Data;
+
+    #[test]
+    fn test_structural_sharing() {
+        let tail = List::from(vec![Data::Int(2), Data::Int(3)]);
+        let list = tail.cons(Data::Int(1));
+        assert_eq!(3, list.len());
+        assert!(List::ptr_eq(&tail, &list.rest().unwrap()));
+        // Two lists consed onto the same tail both keep it.
+        let other = tail.cons(Data::Int(0));
+        assert!(List::ptr_eq(&list.rest().unwrap(), &other.rest().unwrap()));
+        assert_eq!(
+            vec![Data::Int(2), Data::Int(3)],
+            tail.into_iter().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            vec![Data::Int(1), Data::Int(2), Data::Int(3)],
+            list.to_vec()
+        );
+    }
+
+    #[test]
+    fn test_long_list_drop() {
+        let list: List = (0..1_000_000).map(Data::Int).collect();
+        assert_eq!(1_000_000, list.len());
+        drop(list);
+    }
+}
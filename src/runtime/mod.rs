@@ -1,20 +1,50 @@
-use std::{collections::HashMap, fmt::Debug};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
+    fmt::{Debug, Display},
+    hash::{Hash, Hasher},
+    io::{stdout, BufWriter, Write},
+    rc::Rc,
+};
 
 use crate::ast::Node;
 
-mod error;
+pub mod coerce;
+pub mod error;
 mod intrinsic;
+mod json;
+pub mod list;
+mod regex;
 
 use error::Result;
 
-use self::error::LispError;
+use self::{error::LispError, list::List};
 
 pub type Namespace = HashMap<String, Data>;
-pub type IntrinsicRef = &'static dyn Fn(&mut NSStack, &[Node]) -> Result<Data>;
+pub type IntrinsicRef = &'static dyn Fn(&mut Ctx, &[Node]) -> Result<Data>;
+
+/// How the integer arithmetic intrinsics behave when a result doesn't fit.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ArithMode {
+    /// Overflow is reported as a runtime error.
+    #[default]
+    Checked,
+    /// Results wrap around in two's complement.
+    Wrapping,
+    /// Results are clamped to the integer bounds.
+    Saturating,
+}
+
 pub struct NSStack {
     spaces: Vec<Namespace>,
 }
 
+impl Default for NSStack {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 impl NSStack {
     pub fn new() -> Self {
         NSStack {
@@ -31,14 +61,24 @@ impl NSStack {
         Err(LispError::VariableNotFound(name.into()))
     }
 
-    // pub fn lookup_mut(&mut self, name: &str) -> Result<&mut Data> {
-    //     for space in self.spaces.iter_mut().rev() {
-    //         if let Some(d) = space.get_mut(name) {
-    //             return Ok(d);
-    //         }
-    //     }
-    //     Err(LispError::VariableNotFound(name.into()))
-    // }
+    pub fn lookup_mut(&mut self, name: &str) -> Result<&mut Data> {
+        for space in self.spaces.iter_mut().rev() {
+            if let Some(d) = space.get_mut(name) {
+                return Ok(d);
+            }
+        }
+        Err(LispError::VariableNotFound(name.into()))
+    }
+
+    /// How many scopes are open, counting the global one.
+    pub fn depth(&self) -> usize {
+        self.spaces.len()
+    }
+
+    /// Closes scopes until only `depth` are left.
+    pub fn unwind_to(&mut self, depth: usize) {
+        self.spaces.truncate(depth.max(1));
+    }
 
     pub fn enter_scope(&mut self) {
         self.spaces.push(HashMap::new())
@@ -48,69 +88,543 @@ impl NSStack {
         self.spaces.pop();
     }
 
+    /// All visible bindings, sorted by name, with inner scopes shadowing
+    /// outer ones.
+    pub fn bindings(&self) -> Vec<(&str, &Data)> {
+        let mut visible = HashMap::new();
+        for space in &self.spaces {
+            for (name, data) in space {
+                visible.insert(name.as_str(), data);
+            }
+        }
+        let mut bindings: Vec<_> = visible.into_iter().collect();
+        bindings.sort_by_key(|(name, _)| *name);
+        bindings
+    }
+
     pub fn top(&mut self) -> Result<&mut Namespace> {
         self.spaces.last_mut().ok_or(LispError::StackEmpty)
     }
 
     pub fn register_intrinsic(&mut self, name: &str, f: IntrinsicRef) -> Result<()> {
-        let r = Data::Intrinsic(name.into(), f);
+        self.define_global(name, Data::Intrinsic(name.into(), f))
+    }
+
+    /// Binds or, given `None`, unbinds a global name, returning what it was
+    /// bound to before.
+    pub fn replace_global(&mut self, name: &str, data: Option<Data>) -> Result<Option<Data>> {
+        let globals = self.spaces.get_mut(0).ok_or(LispError::StackEmpty)?;
+        Ok(match data {
+            Some(data) => globals.insert(name.into(), data),
+            None => globals.remove(name),
+        })
+    }
+
+    pub fn define_global(&mut self, name: &str, data: Data) -> Result<()> {
         self.spaces
             .get_mut(0)
             .ok_or(LispError::StackEmpty)?
-            .insert(name.into(), r);
+            .insert(name.into(), data);
         Ok(())
     }
 }
 
+/// Everything an intrinsic can observe or change while it runs: the
+/// namespace stack plus the interpreter-wide settings.
+pub struct Ctx {
+    pub stack: NSStack,
+    arith_mode: ArithMode,
+    /// Where printing intrinsics write. Buffered, so it needs flushing before
+    /// anything else is shown to the user.
+    output: BufWriter<Box<dyn Write>>,
+    /// Command-line arguments given to the running script.
+    args: Vec<String>,
+    /// Environment variables set through the runtime, which `getenv` sees
+    /// over the process environment. The process environment itself is
+    /// never changed, as that would leak between runtimes and threads.
+    env: HashMap<String, String>,
+    rng: XorShift,
+    /// Evaluation steps left before the current top-level evaluation is
+    /// aborted, if there is a limit.
+    steps_left: Option<u64>,
+    step_limit: Option<u64>,
+    /// The most elements a collection made by an intrinsic may have, if
+    /// capped.
+    max_collection_size: Option<usize>,
+}
+
+impl Ctx {
+    pub fn new(stack: NSStack) -> Self {
+        Ctx {
+            stack,
+            arith_mode: ArithMode::default(),
+            output: BufWriter::new(Box::new(stdout())),
+            args: Vec::new(),
+            env: HashMap::new(),
+            rng: XorShift::from_time(),
+            steps_left: None,
+            step_limit: None,
+            max_collection_size: None,
+        }
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.output.flush().map_err(output_error)
+    }
+
+    /// Errors if a collection of `len` elements would exceed the configured
+    /// size limit.
+    fn check_collection_size(&self, len: usize) -> Result<()> {
+        match self.max_collection_size {
+            Some(max) if len > max => {
+                Err(LispError::Runtime("collection size limit exceeded".into()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Passes on `data` if it's within the size limit: the items of a list
+    /// or byte string and the characters of a string or string builder
+    /// count. Every intrinsic's result goes through this, so no builtin can
+    /// hand back an oversized collection.
+    fn check_result_size(&self, data: Data) -> Result<Data> {
+        if self.max_collection_size.is_some() {
+            let len = match &data {
+                Data::List(items) => items.len(),
+                Data::Bytes(bytes) => bytes.len(),
+                Data::Str(s) => s.chars().count(),
+                Data::StrBuilder(s) => s.borrow().chars().count(),
+                _ => 0,
+            };
+            self.check_collection_size(len)?;
+        }
+        Ok(data)
+    }
+}
+
+/// The xorshift64 generator behind `random`. Seeded from the clock unless a
+/// script calls `seed!`.
+struct XorShift(u64);
+
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        // Scrambled with a splitmix64 step, since similar seeds would otherwise
+        // start out with similar sequences. The state must not be zero.
+        let mut z = seed.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        XorShift((z ^ (z >> 31)).max(1))
+    }
+
+    fn from_time() -> Self {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default();
+        Self::new(now.as_nanos() as u64)
+    }
+
+    fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x << 13;
+        x ^= x >> 7;
+        x ^= x << 17;
+        self.0 = x;
+        x
+    }
+}
+
+fn output_error(e: std::io::Error) -> LispError {
+    LispError::Runtime(format!("Couldn't write output: {e}"))
+}
+
 pub struct Runtime {
-    stack: NSStack,
+    ctx: Ctx,
+    sandboxed: bool,
+    /// Whether `prelude.lisp` was loaded, so `reset` loads it again.
+    prelude: bool,
+    /// Names defined before any user code ran, to tell them apart from user
+    /// definitions.
+    builtin_names: HashSet<String>,
 }
 
 impl Runtime {
     pub fn try_new() -> Result<Self> {
+        Self::with_builtins(false)
+    }
+
+    /// A runtime for untrusted scripts: intrinsics that touch the host, such
+    /// as reading files or the environment, are left undefined.
+    pub fn sandboxed() -> Result<Self> {
+        Self::with_builtins(true)
+    }
+
+    fn with_builtins(sandboxed: bool) -> Result<Self> {
+        let stack = Self::builtins(sandboxed)?;
+        let builtin_names = Self::binding_names(&stack);
+        Ok(Self {
+            ctx: Ctx::new(stack),
+            sandboxed,
+            prelude: false,
+            builtin_names,
+        })
+    }
+
+    fn builtins(sandboxed: bool) -> Result<NSStack> {
         let mut stack = NSStack::new();
+        stack.define_global("true", Data::Bool(true))?;
+        stack.define_global("false", Data::Bool(false))?;
+        stack.define_global("nil", Data::Empty)?;
+        stack.define_global("nan", Data::Float(f64::NAN))?;
         stack.register_intrinsic("let", &intrinsic::f_let)?;
+        stack.register_intrinsic("swap!", &intrinsic::swap)?;
         stack.register_intrinsic("quote", &intrinsic::quote)?;
+        stack.register_intrinsic("quote-deep", &intrinsic::quote_deep)?;
         stack.register_intrinsic("unquote", &intrinsic::unquote)?;
+        stack.register_intrinsic("syntax-quote", &intrinsic::syntax_quote)?;
+        stack.register_intrinsic("comment", &intrinsic::comment)?;
+        stack.register_intrinsic("eval", &intrinsic::eval)?;
+        stack.register_intrinsic("quote->list", &intrinsic::quote_to_list)?;
+        stack.register_intrinsic("list->quote", &intrinsic::list_to_quote)?;
         stack.register_intrinsic("do", &intrinsic::f_do)?;
+        stack.register_intrinsic("with-scope", &intrinsic::with_scope)?;
+        stack.register_intrinsic("with-redefs", &intrinsic::with_redefs)?;
         stack.register_intrinsic("if", &intrinsic::f_if)?;
+        stack.register_intrinsic("if-let", &intrinsic::if_let)?;
+        stack.register_intrinsic("when-let", &intrinsic::when_let)?;
+        stack.register_intrinsic("match", &intrinsic::f_match)?;
+        stack.register_intrinsic("try-catch", &intrinsic::try_catch)?;
+        stack.register_intrinsic("loop", &intrinsic::f_loop)?;
+        stack.register_intrinsic("recur", &intrinsic::recur)?;
+        stack.register_intrinsic("letrec", &intrinsic::letrec)?;
+        stack.register_intrinsic("letfn", &intrinsic::letfn)?;
         stack.register_intrinsic("fn", &intrinsic::f_fn)?;
+        stack.register_intrinsic("defn", &intrinsic::defn)?;
+        stack.register_intrinsic("doc", &intrinsic::doc)?;
+        stack.register_intrinsic("fn-params", &intrinsic::fn_params)?;
+        stack.register_intrinsic("fn-body", &intrinsic::fn_body)?;
+        stack.register_intrinsic("defmacro", &intrinsic::defmacro)?;
+        stack.register_intrinsic("macroexpand", &intrinsic::macroexpand)?;
+        stack.register_intrinsic("is-bound?", &intrinsic::is_bound)?;
         stack.register_intrinsic("debug", &intrinsic::debug)?;
+        stack.register_intrinsic("flush", &intrinsic::flush)?;
+        stack.register_intrinsic("pprint", &intrinsic::pprint)?;
+        stack.register_intrinsic("time-ms", &intrinsic::time_ms)?;
+        stack.register_intrinsic("random", &intrinsic::random)?;
+        stack.register_intrinsic("seed!", &intrinsic::seed)?;
         stack.register_intrinsic("=", &intrinsic::eq)?;
         stack.register_intrinsic("!=", &intrinsic::ne)?;
+        stack.register_intrinsic("eq?", &intrinsic::is_eq)?;
+        stack.register_intrinsic("equal?", &intrinsic::is_equal)?;
+        stack.register_intrinsic("true?", &intrinsic::is_true)?;
+        stack.register_intrinsic("false?", &intrinsic::is_false)?;
+        stack.register_intrinsic("truthy?", &intrinsic::is_truthy)?;
+        stack.register_intrinsic("<", &intrinsic::lt)?;
+        stack.register_intrinsic(">", &intrinsic::gt)?;
+        stack.register_intrinsic("<=", &intrinsic::le)?;
+        stack.register_intrinsic(">=", &intrinsic::ge)?;
         stack.register_intrinsic("+", &intrinsic::add)?;
         stack.register_intrinsic("-", &intrinsic::sub)?;
         stack.register_intrinsic("*", &intrinsic::mul)?;
         stack.register_intrinsic("/", &intrinsic::div)?;
         stack.register_intrinsic("mod", &intrinsic::modul)?;
-        Ok(Self { stack })
+        stack.register_intrinsic("nan?", &intrinsic::is_nan)?;
+        stack.register_intrinsic("to-float", &intrinsic::to_float)?;
+        stack.register_intrinsic("to-int", &intrinsic::to_int)?;
+        stack.register_intrinsic("round-to-int", &intrinsic::round_to_int)?;
+        stack.register_intrinsic("to-radix", &intrinsic::to_radix)?;
+        stack.register_intrinsic("str->int", &intrinsic::str_to_int)?;
+        stack.register_intrinsic("chars", &intrinsic::chars)?;
+        stack.register_intrinsic("implode", &intrinsic::implode)?;
+        stack.register_intrinsic("upper", &intrinsic::upper)?;
+        stack.register_intrinsic("lower", &intrinsic::lower)?;
+        stack.register_intrinsic("str-eq-ci", &intrinsic::str_eq_ci)?;
+        stack.register_intrinsic("trim", &intrinsic::trim)?;
+        stack.register_intrinsic("starts-with?", &intrinsic::starts_with)?;
+        stack.register_intrinsic("ends-with?", &intrinsic::ends_with)?;
+        stack.register_intrinsic("replace", &intrinsic::replace)?;
+        stack.register_intrinsic("index-of", &intrinsic::index_of)?;
+        stack.register_intrinsic("string-contains", &intrinsic::string_contains)?;
+        stack.register_intrinsic("format", &intrinsic::format)?;
+        stack.register_intrinsic("regex-match", &intrinsic::regex_match)?;
+        stack.register_intrinsic("regex-replace", &intrinsic::regex_replace)?;
+        stack.register_intrinsic("bytes-len", &intrinsic::bytes_len)?;
+        stack.register_intrinsic("byte-at", &intrinsic::byte_at)?;
+        stack.register_intrinsic("list", &intrinsic::list)?;
+        stack.register_intrinsic("cons", &intrinsic::cons)?;
+        stack.register_intrinsic("repeat", &intrinsic::repeat)?;
+        stack.register_intrinsic("remove", &intrinsic::remove)?;
+        stack.register_intrinsic("distinct", &intrinsic::distinct)?;
+        stack.register_intrinsic("dissoc", &intrinsic::dissoc)?;
+        stack.register_intrinsic("map-get", &intrinsic::map_get)?;
+        stack.register_intrinsic("map-has?", &intrinsic::map_has)?;
+        stack.register_intrinsic("car", &intrinsic::car)?;
+        stack.register_intrinsic("cdr", &intrinsic::cdr)?;
+        stack.register_intrinsic("nth", &intrinsic::nth)?;
+        stack.register_intrinsic("first", &intrinsic::first)?;
+        stack.register_intrinsic("second", &intrinsic::second)?;
+        stack.register_intrinsic("third", &intrinsic::third)?;
+        stack.register_intrinsic("last", &intrinsic::last)?;
+        stack.register_intrinsic("empty?", &intrinsic::is_empty)?;
+        stack.register_intrinsic("len", &intrinsic::len)?;
+        stack.register_intrinsic("size-of", &intrinsic::size_of)?;
+        stack.register_intrinsic("type-of", &intrinsic::type_of)?;
+        stack.register_intrinsic("ok", &intrinsic::ok)?;
+        stack.register_intrinsic("err", &intrinsic::err)?;
+        stack.register_intrinsic("ok?", &intrinsic::is_ok)?;
+        stack.register_intrinsic("err?", &intrinsic::is_err)?;
+        stack.register_intrinsic("unwrap", &intrinsic::unwrap)?;
+        stack.register_intrinsic("str-builder", &intrinsic::str_builder)?;
+        stack.register_intrinsic("append!", &intrinsic::append)?;
+        stack.register_intrinsic("builder->str", &intrinsic::builder_to_str)?;
+        stack.register_intrinsic("deep-copy", &intrinsic::deep_copy)?;
+        stack.register_intrinsic("to-json", &intrinsic::to_json)?;
+        stack.register_intrinsic("from-json", &intrinsic::from_json)?;
+        stack.register_intrinsic("->", &intrinsic::thread_first)?;
+        stack.register_intrinsic("->>", &intrinsic::thread_last)?;
+        stack.register_intrinsic("popcount", &intrinsic::popcount)?;
+        stack.register_intrinsic("leading-zeros", &intrinsic::leading_zeros)?;
+        stack.register_intrinsic("trailing-zeros", &intrinsic::trailing_zeros)?;
+        stack.register_intrinsic("even?", &intrinsic::is_even)?;
+        stack.register_intrinsic("odd?", &intrinsic::is_odd)?;
+        stack.register_intrinsic("inc", &intrinsic::inc)?;
+        stack.register_intrinsic("dec", &intrinsic::dec)?;
+        stack.register_intrinsic("map", &intrinsic::map)?;
+        stack.register_intrinsic("reduce", &intrinsic::reduce)?;
+        stack.register_intrinsic("fold-right", &intrinsic::fold_right)?;
+        stack.register_intrinsic("compose", &intrinsic::compose)?;
+        stack.register_intrinsic("sum", &intrinsic::sum)?;
+        stack.register_intrinsic("product", &intrinsic::product)?;
+        stack.register_intrinsic("count-if", &intrinsic::count_if)?;
+        stack.register_intrinsic("partition", &intrinsic::partition)?;
+        stack.register_intrinsic("mean", &intrinsic::mean)?;
+        stack.register_intrinsic("median", &intrinsic::median)?;
+        stack.register_intrinsic("stddev", &intrinsic::stddev)?;
+        stack.register_intrinsic("zip", &intrinsic::zip)?;
+        stack.register_intrinsic("zip-with", &intrinsic::zip_with)?;
+        stack.register_intrinsic("flatten", &intrinsic::flatten)?;
+        stack.register_intrinsic("flatten-1", &intrinsic::flatten_1)?;
+        stack.register_intrinsic("assert", &intrinsic::assert)?;
+        if !sandboxed {
+            stack.register_intrinsic("argv", &intrinsic::argv)?;
+            stack.register_intrinsic("getenv", &intrinsic::getenv)?;
+            stack.register_intrinsic("setenv", &intrinsic::setenv)?;
+            stack.register_intrinsic("read-bytes", &intrinsic::read_bytes)?;
+            stack.register_intrinsic("exit", &intrinsic::exit)?;
+            stack.register_intrinsic("sleep", &intrinsic::sleep)?;
+        }
+        Ok(stack)
+    }
+
+    fn binding_names(stack: &NSStack) -> HashSet<String> {
+        stack
+            .bindings()
+            .into_iter()
+            .map(|(name, _)| name.to_owned())
+            .collect()
+    }
+
+    /// Like `try_new`, but also defines the Lisp-level standard library from
+    /// `prelude.lisp`.
+    pub fn with_prelude() -> Result<Self> {
+        let mut runtime = Self::try_new()?;
+        runtime.eval_program_str(include_str!("prelude.lisp"))?;
+        runtime.prelude = true;
+        // The prelude's definitions aren't the user's either.
+        runtime.builtin_names = Self::binding_names(&runtime.ctx.stack);
+        Ok(runtime)
+    }
+
+    /// Drops every user definition, leaving only the builtins and, if it was
+    /// loaded, the prelude.
+    pub fn reset(&mut self) -> Result<()> {
+        self.ctx.stack = Self::builtins(self.sandboxed)?;
+        if self.prelude {
+            self.eval_program_str(include_str!("prelude.lisp"))?;
+        }
+        Ok(())
+    }
+
+    pub fn bindings(&self) -> Vec<(&str, &Data)> {
+        self.ctx.stack.bindings()
+    }
+
+    /// The visible bindings whose names weren't defined by the runtime
+    /// itself, sorted by name. Redefining a builtin doesn't make it a user
+    /// definition.
+    pub fn user_bindings(&self) -> Vec<(&str, &Data)> {
+        let mut bindings = self.bindings();
+        bindings.retain(|(name, _)| !self.builtin_names.contains(*name));
+        bindings
+    }
+
+    /// Evaluates one form. Comments kept by the parser are skipped, so
+    /// commented code means the same as it does without them.
+    pub fn eval(&mut self, mut node: Node) -> Result<Data> {
+        self.ctx.steps_left = self.ctx.step_limit;
+        node.strip_comments();
+        node.eval(&mut self.ctx)
+    }
+
+    /// Evaluates every form in `src`, stopping at the first error, and returns
+    /// the value of the last one.
+    pub fn eval_program_str(&mut self, src: &str) -> Result<Data> {
+        let mut result = Data::Empty;
+        for node in crate::parser::program(src.as_bytes())? {
+            result = self.eval(node)?;
+        }
+        Ok(result)
+    }
+
+    /// Evaluates every form in `src`, carrying on past errors, and returns
+    /// one result per form. If `src` doesn't parse, nothing is evaluated and
+    /// the parse error is the only result.
+    pub fn eval_all(&mut self, src: &str) -> Vec<Result<Data>> {
+        match crate::parser::program(src.as_bytes()) {
+            Ok(nodes) => nodes.into_iter().map(|node| self.eval(node)).collect(),
+            Err(e) => vec![Err(e)],
+        }
+    }
+
+    pub fn register_intrinsic(&mut self, name: &str, f: IntrinsicRef) -> Result<()> {
+        self.ctx.stack.register_intrinsic(name, f)
     }
 
-    pub fn eval(&mut self, node: Node) -> Result<Data> {
-        node.eval(&mut self.stack)
+    /// Sends printed output to `out` instead of stdout, after flushing
+    /// whatever is pending for the previous sink.
+    pub fn set_output(&mut self, out: impl Write + 'static) -> Result<()> {
+        self.ctx.flush()?;
+        self.ctx.output = BufWriter::new(Box::new(out));
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        self.ctx.flush()
+    }
+
+    /// Sets what `(argv)` returns.
+    pub fn set_args(&mut self, args: Vec<String>) {
+        self.ctx.args = args;
+    }
+
+    /// Sets an environment variable for `getenv`, as `setenv` does, without
+    /// touching the process environment.
+    pub fn set_env(&mut self, name: &str, value: &str) {
+        self.ctx.env.insert(name.into(), value.into());
+    }
+
+    /// Caps how many expressions a single call to `eval` may evaluate, so
+    /// that runaway scripts end with an error. `None` removes the cap.
+    pub fn set_step_limit(&mut self, limit: Option<u64>) {
+        self.ctx.step_limit = limit;
+    }
+
+    /// Caps how many elements a list, string or byte string made by an
+    /// intrinsic such as `repeat`, `cons` or `implode` may have. Intrinsics
+    /// that know the size up front, like `repeat`, fail before building
+    /// anything. `None` removes the cap.
+    pub fn set_max_collection_size(&mut self, max: Option<usize>) {
+        self.ctx.max_collection_size = max;
+    }
+
+    pub fn set_arith_mode(&mut self, mode: ArithMode) {
+        self.ctx.arith_mode = mode;
     }
 }
 
+/// Cloning a value copies it, except for the reference types: lists,
+/// functions and macros, which can't change, and string builders, which
+/// can. Binding a builder with `let`, passing it to a function or putting it
+/// in a list all share the one builder, so appending through any of them is
+/// seen by all; `deep-copy` makes an independent one.
 #[derive(Clone)]
 pub enum Data {
     Quote(Node),
+    Bool(bool),
     Int(i32),
+    Float(f64),
     Str(String),
-    // String()
+    Bytes(Vec<u8>),
+    List(List),
     Intrinsic(String, IntrinsicRef),
-    Function(Vec<String>, Node),
+    Function(Rc<Lambda>),
+    /// Like a function, but receives its arguments as unevaluated quotes and
+    /// returns code, which is then evaluated in place of the call.
+    Macro(Rc<Lambda>),
+    /// A successful result from `(ok x)`.
+    Ok(Box<Data>),
+    /// A failed result from `(err msg)`.
+    Err(Box<Data>),
+    /// A growable string from `str-builder`, shared between its copies.
+    StrBuilder(Rc<RefCell<String>>),
     Empty,
 }
 
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lambda {
+    pub params: Vec<String>,
+    /// Conditions from a leading `(pre ...)` form, checked before the body.
+    pub pre: Vec<Node>,
+    pub body: Node,
+    /// The docstring given to `defn`, if any.
+    pub doc: Option<String>,
+    /// Arguments supplied by partial application, bound alongside `params`
+    /// when the function is finally called.
+    pub captured: Vec<(String, Data)>,
+}
+
+impl Lambda {
+    // Expects the parameters to already be bound in the innermost scope.
+    fn run(&self, ctx: &mut Ctx) -> Result<Data> {
+        for condition in &self.pre {
+            if !condition.eval(ctx)?.is_truthy() {
+                return Err(LispError::Runtime(format!(
+                    "Precondition {} failed.",
+                    condition.to_source()
+                )));
+            }
+        }
+        self.body.eval(ctx)
+    }
+
+    /// Runs the lambda as a macro: every argument is bound to its code as a
+    /// quote, and the result is turned back into code.
+    fn expand(&self, ctx: &mut Ctx, params: &[Node]) -> Result<Node> {
+        if params.len() != self.params.len() {
+            return Err(LispError::SyntaxError("Wrong macro argument count.".into()));
+        }
+        ctx.stack.enter_scope();
+        let scope = ctx.stack.top()?;
+        for (name, param) in self.params.iter().zip(params) {
+            scope.insert(name.clone(), Data::Quote(param.clone()));
+        }
+        let r = self.run(ctx);
+        ctx.stack.exit_scope();
+        r?.to_node()
+    }
+}
+
+/// Values compare structurally, except for functions: two functions are
+/// equal only if they come from the same `fn` evaluation (bindings and
+/// copies of one function share it). Comparing parameters and bodies would
+/// call two functions equal even when they capture different values.
 impl PartialEq for Data {
     fn eq(&self, other: &Self) -> bool {
         match (self, other) {
             (Self::Quote(l0), Self::Quote(r0)) => l0 == r0,
+            (Self::Bool(l0), Self::Bool(r0)) => l0 == r0,
             (Self::Int(l0), Self::Int(r0)) => l0 == r0,
+            (Self::Float(l0), Self::Float(r0)) => l0 == r0,
             (Self::Str(l0), Self::Str(r0)) => l0 == r0,
+            (Self::Bytes(l0), Self::Bytes(r0)) => l0 == r0,
+            (Self::List(l0), Self::List(r0)) => l0 == r0,
             (Self::Intrinsic(l0, _), Self::Intrinsic(r0, _)) => l0 == r0,
-            (Self::Function(l0, l1), Self::Function(r0, r1)) => l0 == r0 && l1 == r1,
-            _ => core::mem::discriminant(self) == core::mem::discriminant(other),
+            (Self::Function(l0), Self::Function(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Macro(l0), Self::Macro(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Ok(l0), Self::Ok(r0)) => l0 == r0,
+            (Self::Err(l0), Self::Err(r0)) => l0 == r0,
+            (Self::StrBuilder(l0), Self::StrBuilder(r0)) => Rc::ptr_eq(l0, r0),
+            (Self::Empty, Self::Empty) => true,
+            _ => false,
         }
     }
 }
@@ -119,68 +633,374 @@ impl Debug for Data {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Self::Quote(arg0) => f.debug_tuple("Quote").field(arg0).finish(),
+            Self::Bool(arg0) => f.debug_tuple("Bool").field(arg0).finish(),
             Self::Int(arg0) => f.debug_tuple("Int").field(arg0).finish(),
+            Self::Float(arg0) => f.debug_tuple("Float").field(arg0).finish(),
             Self::Str(arg0) => f.debug_tuple("Str").field(arg0).finish(),
+            Self::Bytes(arg0) => f.debug_tuple("Bytes").field(arg0).finish(),
+            Self::List(arg0) => f.debug_tuple("List").field(arg0).finish(),
             Self::Intrinsic(arg0, _) => f.debug_tuple("Intrinsic").field(arg0).finish(),
-            Self::Function(arg0, arg1) => {
-                f.debug_tuple("Function").field(arg0).field(arg1).finish()
-            }
+            Self::Function(lambda) => f
+                .debug_tuple("Function")
+                .field(&lambda.params)
+                .field(&lambda.body)
+                .finish(),
+            Self::Macro(lambda) => f
+                .debug_tuple("Macro")
+                .field(&lambda.params)
+                .field(&lambda.body)
+                .finish(),
+            Self::Ok(arg0) => f.debug_tuple("Ok").field(arg0).finish(),
+            Self::Err(arg0) => f.debug_tuple("Err").field(arg0).finish(),
+            Self::StrBuilder(s) => f.debug_tuple("StrBuilder").field(&s.borrow()).finish(),
             Self::Empty => write!(f, "Empty"),
         }
     }
 }
 
+/// Renders values the way they would be written in source where possible, so
+/// quotes use the `'` reader syntax.
+impl Display for Data {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Quote(node) => write!(f, "'{}", node.to_source()),
+            Self::Bool(b) => write!(f, "{b}"),
+            Self::Int(i) => write!(f, "{i}"),
+            Self::Float(x) => write!(f, "{x:?}"),
+            Self::Str(s) => write!(f, "{s:?}"),
+            Self::Bytes(b) => write!(f, "<{} bytes>", b.len()),
+            Self::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, ")")
+            }
+            Self::Intrinsic(name, _) => write!(f, "<intrinsic {name}>"),
+            Self::Function(lambda) => write!(f, "<fn ({})>", lambda.params.join(" ")),
+            Self::Macro(lambda) => write!(f, "<macro ({})>", lambda.params.join(" ")),
+            Self::Ok(value) => write!(f, "(ok {value})"),
+            Self::Err(value) => write!(f, "(err {value})"),
+            Self::StrBuilder(s) => write!(f, "<str-builder {:?}>", s.borrow()),
+            Self::Empty => write!(f, "nil"),
+        }
+    }
+}
+
 impl Data {
-    fn exec(&self, stack: &mut NSStack, params: &[Node]) -> Result<Data> {
+    pub fn list(items: Vec<Data>) -> Data {
+        Data::List(items.into())
+    }
+
+    fn exec(&self, ctx: &mut Ctx, params: &[Node]) -> Result<Data> {
         match self {
-            Data::Intrinsic(_, f) => f(stack, params),
-            Data::Function(argnames, body) => {
-                if params.len() != argnames.len() {
+            Data::Intrinsic(_, f) => f(ctx, params).and_then(|data| ctx.check_result_size(data)),
+            Data::Function(lambda) => {
+                let argnames = &lambda.params;
+                if params.len() > argnames.len() {
                     return Err(LispError::SyntaxError(
                         "Wrong function argument count.".into(),
                     ));
                 }
-                let mut k = HashMap::new();
+                let mut k: HashMap<_, _> = lambda.captured.iter().cloned().collect();
                 for (i, param) in params.iter().enumerate() {
-                    let param_data = param.eval(stack)?;
+                    let param_data = param.eval(ctx)?;
                     k.insert(argnames[i].clone(), param_data.clone());
                 }
-                stack.spaces.push(k);
-                let r = body.eval(stack);
-                stack.exit_scope();
-                r
+                // Too few arguments curry: the result waits for the rest.
+                if params.len() < argnames.len() {
+                    return Ok(Data::Function(Rc::new(Lambda {
+                        params: argnames[params.len()..].to_vec(),
+                        captured: k.into_iter().collect(),
+                        doc: None,
+                        ..Lambda::clone(lambda)
+                    })));
+                }
+                ctx.stack.enter_scope();
+                ctx.stack.top()?.extend(k);
+                let r = lambda.run(ctx);
+                ctx.stack.exit_scope();
+                // Letting it through would restart whichever loop called the
+                // function.
+                match r {
+                    Err(LispError::Recur(_)) => Err(LispError::SyntaxError(
+                        "recur can't reach a loop outside its function.".into(),
+                    )),
+                    r => r,
+                }
             }
+            Data::Macro(lambda) => lambda.expand(ctx, params)?.eval(ctx),
             _ => Err(LispError::TypeError(format!("{:?} is not callable.", self))),
         }
     }
 
-    fn is_truthy(&self) -> bool {
+    /// Renders the value like `Display`, but shows at most `max_items` items
+    /// of each list, followed by how many were left out.
+    pub fn display_truncated(&self, max_items: usize) -> String {
+        match self {
+            Data::List(items) => {
+                let mut shown: Vec<_> = items
+                    .iter()
+                    .take(max_items)
+                    .map(|item| item.display_truncated(max_items))
+                    .collect();
+                if items.len() > max_items {
+                    shown.push(format!("... {} more", items.len() - max_items));
+                }
+                format!("({})", shown.join(" "))
+            }
+            Data::Ok(value) => format!("(ok {})", value.display_truncated(max_items)),
+            Data::Err(value) => format!("(err {})", value.display_truncated(max_items)),
+            d => d.to_string(),
+        }
+    }
+
+    /// A rough count of the bytes the value takes up, its own slot included.
+    /// Shared parts, like the tail of a list consed onto another, are counted
+    /// once per value that reaches them.
+    pub fn approx_size(&self) -> usize {
+        let slot = std::mem::size_of::<Data>();
+        slot + match self {
+            Data::Quote(node) => node.node_count() * std::mem::size_of::<Node>(),
+            Data::Str(s) => s.len(),
+            Data::Bytes(b) => b.len(),
+            // Each cell holds its item plus a tail pointer, length and
+            // reference counts.
+            Data::List(items) => items
+                .iter()
+                .map(|item| item.approx_size() + 4 * std::mem::size_of::<usize>())
+                .sum(),
+            Data::Function(lambda) | Data::Macro(lambda) => {
+                lambda.body.node_count() * std::mem::size_of::<Node>()
+            }
+            Data::Ok(value) | Data::Err(value) => value.approx_size(),
+            Data::StrBuilder(s) => s.borrow().capacity(),
+            Data::Bool(_) | Data::Int(_) | Data::Float(_) | Data::Intrinsic(..) | Data::Empty => 0,
+        }
+    }
+
+    /// The name `type-of` gives for the value's type.
+    pub fn type_name(&self) -> &'static str {
         match self {
-            Data::Quote(q) => *q == Node::Identifier("true".into()),
-            Data::Int(i) => *i != 0,
-            Data::Str(s) => !s.is_empty(),
-            Data::Intrinsic(_, _) => false,
-            Data::Function(_, _) => false,
-            Data::Empty => false,
+            Data::Quote(_) => "quote",
+            Data::Bool(_) => "bool",
+            Data::Int(_) => "int",
+            Data::Float(_) => "float",
+            Data::Str(_) => "string",
+            Data::Bytes(_) => "bytes",
+            Data::List(_) => "list",
+            Data::Intrinsic(..) => "intrinsic",
+            Data::Function(_) => "function",
+            Data::Macro(_) => "macro",
+            Data::Ok(_) => "ok",
+            Data::Err(_) => "err",
+            Data::StrBuilder(_) => "str-builder",
+            Data::Empty => "nil",
         }
     }
+
+    /// Calls the value with arguments that have already been evaluated.
+    pub fn apply(&self, ctx: &mut Ctx, args: Vec<Data>) -> Result<Data> {
+        // Intrinsics only accept unevaluated nodes, so the arguments are bound
+        // in a scratch scope under names no identifier can spell and passed
+        // by reference.
+        ctx.stack.enter_scope();
+        let mut nodes = Vec::with_capacity(args.len());
+        for (i, arg) in args.into_iter().enumerate() {
+            let name = format!(" arg{i}");
+            ctx.stack.top()?.insert(name.clone(), arg);
+            nodes.push(Node::Identifier(name));
+        }
+        let r = self.exec(ctx, &nodes);
+        ctx.stack.exit_scope();
+        r
+    }
+
+    /// Whether the two are the same value rather than merely equal ones: lists
+    /// must be the very same cells, and functions, macros and string builders
+    /// the same object. Everything else is copied when passed around, so it's
+    /// compared by value, as `==` does.
+    pub fn is_identical(&self, other: &Data) -> bool {
+        match (self, other) {
+            (Data::List(a), Data::List(b)) => List::ptr_eq(a, b),
+            (Data::Ok(a), Data::Ok(b)) | (Data::Err(a), Data::Err(b)) => a.is_identical(b),
+            _ => self == other,
+        }
+    }
+
+    /// A hash that agrees with `==`: equal values hash alike. Quoted code has
+    /// none, and neither does a list containing it.
+    pub fn hash_value(&self) -> Option<u64> {
+        let mut hasher = DefaultHasher::new();
+        self.feed_hash(&mut hasher).then(|| hasher.finish())
+    }
+
+    fn feed_hash(&self, hasher: &mut DefaultHasher) -> bool {
+        std::mem::discriminant(self).hash(hasher);
+        match self {
+            Data::Quote(_) => return false,
+            Data::Bool(b) => b.hash(hasher),
+            Data::Int(i) => i.hash(hasher),
+            // 0.0 and -0.0 are equal, so they must hash alike.
+            Data::Float(x) => (if *x == 0.0 { 0.0 } else { *x }).to_bits().hash(hasher),
+            Data::Str(s) => s.hash(hasher),
+            Data::Bytes(b) => b.hash(hasher),
+            Data::List(items) => {
+                items.len().hash(hasher);
+                return items.iter().all(|item| item.feed_hash(hasher));
+            }
+            Data::Intrinsic(name, _) => name.hash(hasher),
+            Data::Function(lambda) | Data::Macro(lambda) => Rc::as_ptr(lambda).hash(hasher),
+            Data::Ok(value) | Data::Err(value) => return value.feed_hash(hasher),
+            Data::StrBuilder(s) => Rc::as_ptr(s).hash(hasher),
+            Data::Empty => {}
+        }
+        true
+    }
+
+    /// Copies the value, giving it fresh copies of any string builders inside
+    /// rather than sharing them.
+    pub fn deep_copy(&self) -> Data {
+        match self {
+            Data::List(items) => Data::List(items.iter().map(Data::deep_copy).collect()),
+            Data::Ok(value) => Data::Ok(Box::new(value.deep_copy())),
+            Data::Err(value) => Data::Err(Box::new(value.deep_copy())),
+            Data::StrBuilder(s) => Data::StrBuilder(Rc::new(RefCell::new(s.borrow().clone()))),
+            d => d.clone(),
+        }
+    }
+
+    /// Turns the value back into code that evaluates to it. Quotes are
+    /// spliced in as the code they hold.
+    pub fn to_node(&self) -> Result<Node> {
+        Ok(match self {
+            Data::Quote(node) => node.clone(),
+            Data::Bool(b) => Node::BoolLiteral(*b),
+            Data::Int(i) => Node::IntegerLiteral(*i),
+            Data::Float(f) => Node::FloatLiteral(*f),
+            Data::Str(s) => Node::StringLiteral(s.clone()),
+            Data::List(items) => {
+                Node::List(items.iter().map(Data::to_node).collect::<Result<_>>()?)
+            }
+            Data::Intrinsic(name, _) => Node::Identifier(name.clone()),
+            Data::Ok(value) => Node::List(vec![Node::Identifier("ok".into()), value.to_node()?]),
+            Data::Err(value) => Node::List(vec![Node::Identifier("err".into()), value.to_node()?]),
+            _ => {
+                return Err(LispError::TypeError(format!(
+                    "{:?} can't be turned into code.",
+                    self
+                )))
+            }
+        })
+    }
+
+    /// Turns code into data: lists become lists and literals their values,
+    /// all the way down. Identifiers and quoted forms have no value of their
+    /// own, so they stay quoted. Roughly the inverse of `to_node`.
+    pub fn quote_deep(node: &Node) -> Data {
+        match node {
+            Node::List(nodes) => Data::list(
+                nodes
+                    .iter()
+                    .filter(|node| !matches!(node, Node::Comment(_)))
+                    .map(Data::quote_deep)
+                    .collect(),
+            ),
+            Node::StringLiteral(s) => Data::Str(s.clone()),
+            Node::IntegerLiteral(i) => Data::Int(*i),
+            Node::FloatLiteral(f) => Data::Float(*f),
+            Node::BoolLiteral(b) => Data::Bool(*b),
+            Node::Comment(_) => Data::Empty,
+            Node::Identifier(_) | Node::Quote(_) => Data::Quote(node.clone()),
+        }
+    }
+
+    /// Only `false` and `nil` are falsy; every other value, including zero
+    /// and empty collections, is truthy.
+    fn is_truthy(&self) -> bool {
+        !matches!(self, Data::Bool(false) | Data::Empty)
+    }
+}
+
+impl From<i32> for Data {
+    fn from(i: i32) -> Self {
+        Data::Int(i)
+    }
+}
+
+impl From<f64> for Data {
+    fn from(f: f64) -> Self {
+        Data::Float(f)
+    }
+}
+
+impl From<bool> for Data {
+    fn from(b: bool) -> Self {
+        Data::Bool(b)
+    }
+}
+
+impl From<String> for Data {
+    fn from(s: String) -> Self {
+        Data::Str(s)
+    }
+}
+
+impl From<&str> for Data {
+    fn from(s: &str) -> Self {
+        Data::Str(s.into())
+    }
+}
+
+impl TryFrom<Data> for i32 {
+    type Error = LispError;
+
+    fn try_from(d: Data) -> Result<Self> {
+        coerce::expect_int(&d, &format!("{:?}", d))
+    }
+}
+
+impl TryFrom<Data> for String {
+    type Error = LispError;
+
+    fn try_from(d: Data) -> Result<Self> {
+        coerce::expect_str(&d, &format!("{:?}", d)).map(str::to_owned)
+    }
 }
 
 impl Node {
-    pub fn eval(&self, stack: &mut NSStack) -> Result<Data> {
+    pub fn eval(&self, ctx: &mut Ctx) -> Result<Data> {
+        if let Some(steps) = &mut ctx.steps_left {
+            if *steps == 0 {
+                return Err(LispError::Runtime("evaluation step limit exceeded".into()));
+            }
+            *steps -= 1;
+        }
         Ok(match self {
-            Node::Identifier(x) => stack.lookup(x)?.clone(),
+            Node::Identifier(x) => ctx.stack.lookup(x)?.clone(),
             Node::List(ops) => {
                 let fun = ops
-                    .get(0)
+                    .first()
                     .ok_or(LispError::SyntaxError(
                         "List expression with zero arguments.".into(),
                     ))?
-                    .eval(stack)?;
-                fun.exec(stack, &ops[1..])?
+                    .eval(ctx)?;
+                // A quoted name in call position, as in ('+ 1 2) or a name
+                // computed by unquote, calls whatever the name is bound to.
+                let fun = match fun {
+                    Data::Quote(Node::Identifier(name)) => ctx.stack.lookup(&name)?.clone(),
+                    fun => fun,
+                };
+                fun.exec(ctx, &ops[1..])?
             }
             Node::StringLiteral(s) => Data::Str(s.clone()),
             Node::IntegerLiteral(i) => Data::Int(*i),
+            Node::FloatLiteral(f) => Data::Float(*f),
+            Node::BoolLiteral(b) => Data::Bool(*b),
+            Node::Comment(_) => Data::Empty,
             Node::Quote(boxed) => Data::Quote(*boxed.clone()),
         })
     }
@@ -190,7 +1010,17 @@ impl Node {
 mod test {
     use crate::{ast::Node, runtime::Data};
 
-    use super::{error::Result, Runtime};
+    use super::{
+        coerce,
+        error::{LispError, Result},
+        list::List,
+        ArithMode, Ctx, NSStack, Runtime,
+    };
+
+    fn eval_str(runtime: &mut Runtime, src: &str) -> Result<Data> {
+        let (_, node) = crate::parser::node(src.as_bytes()).unwrap();
+        runtime.eval(node)
+    }
 
     #[test]
     fn test_quote_unquote() -> Result<()> {
@@ -203,11 +1033,2034 @@ mod test {
                 Node::IntegerLiteral(2),
                 Node::IntegerLiteral(3)
             ])),
-            runtime.stack.lookup("quoted")?
+            runtime.ctx.stack.lookup("quoted")?
         );
         let (_, node2) = crate::parser::node(b"(let unquoted (unquote quoted))").unwrap();
         runtime.eval(node2).unwrap();
-        assert_eq!(&Data::Int(3), runtime.stack.lookup("unquoted")?);
+        assert_eq!(&Data::Int(3), runtime.ctx.stack.lookup("unquoted")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_let_rebinds_in_same_scope() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(2),
+            eval_str(&mut runtime, "(do (let x 1) (let x 2) x)")?
+        );
+        assert!(runtime.ctx.stack.lookup("x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_let_shadows_outer_scope() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let x 1)")?;
+        eval_str(&mut runtime, "(let f (fn (y) (do (let x y) x)))")?;
+        assert_eq!(Data::Int(5), eval_str(&mut runtime, "(f 5)")?);
+        assert_eq!(&Data::Int(1), runtime.ctx.stack.lookup("x")?);
+
+        let mut stack = NSStack::new();
+        stack.top()?.insert("x".into(), Data::Int(1));
+        stack.enter_scope();
+        stack.top()?.insert("x".into(), Data::Int(2));
+        assert_eq!(&Data::Int(2), stack.lookup("x")?);
+        stack.exit_scope();
+        assert_eq!(&Data::Int(1), stack.lookup("x")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_unquote() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(unquote (quote 1))")?);
+        assert_eq!(
+            Data::Quote(Node::Identifier("x".into())),
+            eval_str(&mut runtime, "(unquote (quote (quote x)))")?
+        );
+        assert_eq!(
+            Data::Quote(Node::IntegerLiteral(1)),
+            eval_str(&mut runtime, "(unquote ''1)")?
+        );
+        assert_eq!(
+            Data::Int(1),
+            eval_str(&mut runtime, "(unquote (unquote ''1))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_arith_modes() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert!(eval_str(&mut runtime, "(+ 2147483647 1)").is_err());
+        assert!(eval_str(&mut runtime, "(/ 1 0)").is_err());
+
+        runtime.set_arith_mode(ArithMode::Wrapping);
+        assert_eq!(
+            Data::Int(i32::MIN),
+            eval_str(&mut runtime, "(+ 2147483647 1)")?
+        );
+
+        runtime.set_arith_mode(ArithMode::Saturating);
+        assert_eq!(
+            Data::Int(i32::MAX),
+            eval_str(&mut runtime, "(+ 2147483647 1)")?
+        );
+        assert!(eval_str(&mut runtime, "(mod 1 0)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_division_by_zero() -> Result<()> {
+        for mode in [
+            ArithMode::Checked,
+            ArithMode::Wrapping,
+            ArithMode::Saturating,
+        ] {
+            let mut runtime = Runtime::try_new()?;
+            runtime.set_arith_mode(mode);
+            for src in ["(/ 7 0)", "(mod 7 0)", "(/ 7 (- 1 1))"] {
+                match eval_str(&mut runtime, src) {
+                    Err(LispError::Runtime(msg)) => assert_eq!("Division by zero.", msg),
+                    r => panic!("unexpected {:?} for {src}", r),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn arith_mode_name(ctx: &mut Ctx, _args: &[Node]) -> Result<Data> {
+        Ok(Data::Str(format!("{:?}", ctx.arith_mode)))
+    }
+
+    #[test]
+    fn test_intrinsics_receive_ctx() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        runtime.register_intrinsic("arith-mode", &arith_mode_name)?;
+        runtime.set_arith_mode(ArithMode::Saturating);
+        assert_eq!(
+            Data::Str("Saturating".into()),
+            eval_str(&mut runtime, "(arith-mode)")?
+        );
+        assert_eq!(
+            Data::Int(6),
+            eval_str(
+                &mut runtime,
+                "(do (let sq (fn (x) (* x x))) (if (= 1 1) (+ (sq 2) 2) 0))"
+            )?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_reset() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let x 1)")?;
+        assert!(runtime.bindings().contains(&("x", &Data::Int(1))));
+        runtime.reset()?;
+        assert!(runtime.ctx.stack.lookup("x").is_err());
+        assert!(runtime.ctx.stack.lookup("+").is_ok());
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(+ 1 2)")?);
+        assert!(runtime.ctx.stack.lookup("fact").is_err());
+
+        let mut runtime = Runtime::with_prelude()?;
+        eval_str(&mut runtime, "(let x 1)")?;
+        eval_str(&mut runtime, "(let fact 0)")?;
+        runtime.reset()?;
+        assert!(runtime.ctx.stack.lookup("x").is_err());
+        assert_eq!(Data::Int(120), eval_str(&mut runtime, "(fact 5)")?);
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(max 1 3)")?);
+        assert!(runtime.user_bindings().is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_bytes() -> Result<()> {
+        let path = std::env::temp_dir().join("nom-lisp-test-read-bytes.bin");
+        std::fs::write(&path, [0xde, 0xad, 0xbe, 0xef]).unwrap();
+        let mut runtime = Runtime::try_new()?;
+        let src = format!("(let b (read-bytes {:?}))", path.to_str().unwrap());
+        eval_str(&mut runtime, &src)?;
+        assert_eq!(
+            &Data::Bytes(vec![0xde, 0xad, 0xbe, 0xef]),
+            runtime.ctx.stack.lookup("b")?
+        );
+        assert_eq!(Data::Int(4), eval_str(&mut runtime, "(bytes-len b)")?);
+        assert_eq!(Data::Int(0xbe), eval_str(&mut runtime, "(byte-at b 2)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(byte-at b 4)"),
+            Err(LispError::Runtime(_))
+        ));
+        assert!(matches!(
+            eval_str(&mut runtime, "(read-bytes \"/nonexistent/nom-lisp\")"),
+            Err(LispError::Runtime(_))
+        ));
+        std::fs::remove_file(path).unwrap();
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_round_trip() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(
+            &mut runtime,
+            "(let x (list 1 \"a\" (list (list) (- 0 2)) \"\"))",
+        )?;
+        assert_eq!(
+            Data::Str(r#"[1,"a",[[],-2],""]"#.into()),
+            eval_str(&mut runtime, "(to-json x)")?
+        );
+        assert_eq!(
+            runtime.ctx.stack.lookup("x")?.clone(),
+            eval_str(&mut runtime, "(from-json (to-json x))")?
+        );
+        assert_eq!(
+            Data::list(vec![
+                Data::Str("q\"\n\u{e9}".into()),
+                Data::Empty,
+                Data::Bool(true)
+            ]),
+            super::json::from_json(r#" [ "q\"\n\u00e9" , null,true ] "#)?
+        );
+        assert_eq!(
+            Data::list(vec![Data::Float(1.5), Data::Float(-200.0), Data::Int(3)]),
+            super::json::from_json("[1.5, -2e2, 3]")?
+        );
+        assert!(eval_str(&mut runtime, "(to-json +)").is_err());
+        assert!(super::json::from_json("[1,").is_err());
+        assert!(super::json::from_json(r#"{"a" 1}"#).is_err());
+
+        let obj = super::json::from_json(r#"{"a": 1, "b" : {"c": [true]}, "d": {}}"#)?;
+        runtime.ctx.stack.define_global("obj", obj)?;
+        assert_eq!(
+            Data::Int(1),
+            eval_str(&mut runtime, r#"(map-get obj "a")"#)?
+        );
+        assert_eq!(
+            Data::list(vec![Data::Bool(true)]),
+            eval_str(&mut runtime, r#"(map-get (map-get obj "b") "c")"#)?
+        );
+        assert_eq!(
+            Data::Str(r#"{"a":1,"b":{"c":[true]},"d":[]}"#.into()),
+            eval_str(&mut runtime, "(to-json obj)")?
+        );
+        // Lists that aren't string-keyed entries stay arrays.
+        assert_eq!(
+            Data::Str(r#"[[1,2],["a"]]"#.into()),
+            eval_str(&mut runtime, "(to-json (list (list 1 2) (list \"a\")))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_json_nesting_limit() -> Result<()> {
+        let max = super::json::MAX_DEPTH;
+        let nested = |depth: usize, open: &str, close: &str| {
+            format!("{}1{}", open.repeat(depth), close.repeat(depth))
+        };
+        assert!(super::json::from_json(&nested(max, "[", "]")).is_ok());
+        for src in [
+            nested(max + 1, "[", "]"),
+            nested(2000, "[", "]"),
+            nested(100_000, "{\"a\":", "}"),
+        ] {
+            match super::json::from_json(&src) {
+                Err(LispError::Runtime(msg)) => {
+                    assert_eq!(format!("JSON nested more than {max} levels deep"), msg)
+                }
+                r => panic!("unexpected {:?}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_first() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let inc (fn (x) (+ x 1)))")?;
+        assert_eq!(Data::Int(8), eval_str(&mut runtime, "(-> 5 (- 1) (* 2))")?);
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(-> 1 inc)")?);
+        assert_eq!(
+            Data::Int(-5),
+            eval_str(&mut runtime, "(-> 10 inc (- 3) (- 13))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_thread_last() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(9),
+            eval_str(&mut runtime, "(->> (list 1 2 3) (map inc) (reduce + 0))")?
+        );
+        assert_eq!(Data::Int(-1), eval_str(&mut runtime, "(->> 5 (- 4))")?);
+        eval_str(&mut runtime, "(let double (fn (x) (* x 2)))")?;
+        assert_eq!(
+            Data::list(vec![Data::Int(2), Data::Int(4)]),
+            eval_str(&mut runtime, "(->> (list 1 2) (map double))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_fn_precondition() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let f (fn (x) (pre (> x 0)) (* x 2)))")?;
+        assert_eq!(Data::Int(4), eval_str(&mut runtime, "(f 2)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(f 0)"),
+            Err(LispError::Runtime(_))
+        ));
+        assert!(eval_str(&mut runtime, "(fn (x) (* x 2) x)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_float_conversions() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Float(3.0), eval_str(&mut runtime, "(to-float 3)")?);
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(to-int 3.9)")?);
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(round-to-int 3.4)")?);
+        assert_eq!(Data::Int(4), eval_str(&mut runtime, "(round-to-int 3.5)")?);
+        assert_eq!(Data::Int(-3), eval_str(&mut runtime, "(to-int (- 0 3.9))")?);
+        assert_eq!(Data::Int(7), eval_str(&mut runtime, "(to-int 7)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(to-int \"3\")"),
+            Err(LispError::InExpression { .. })
+        ));
+        assert!(eval_str(&mut runtime, "(to-int (* 3000000.0 1000.0))").is_err());
+        assert_eq!(Data::Float(2.5), eval_str(&mut runtime, "(+ 2 0.5)")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(< 2 2.5)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_zip() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let pair = |i, s: &str| Data::list(vec![Data::Int(i), Data::Str(s.into())]);
+        assert_eq!(
+            Data::list(vec![pair(1, "a"), pair(2, "b"), pair(3, "c")]),
+            eval_str(&mut runtime, "(zip (list 1 2 3) (list \"a\" \"b\" \"c\"))")?
+        );
+        assert_eq!(
+            Data::list(vec![pair(1, "a")]),
+            eval_str(&mut runtime, "(zip (list 1 2 3) (list \"a\"))")?
+        );
+        assert_eq!(
+            Data::list(vec![Data::Int(11), Data::Int(22)]),
+            eval_str(&mut runtime, "(zip-with + (list 1 2) (list 10 20 30))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_case_conversion() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Str("ABC".into()),
+            eval_str(&mut runtime, "(upper \"abc\")")?
+        );
+        assert_eq!(
+            Data::Str("abc".into()),
+            eval_str(&mut runtime, "(lower \"ABC\")")?
+        );
+        assert_eq!(
+            Data::Str("Ä".into()),
+            eval_str(&mut runtime, "(upper \"ä\")")?
+        );
+        assert_eq!(
+            Data::Bool(true),
+            eval_str(&mut runtime, "(str-eq-ci \"Foo\" \"fOO\")")?
+        );
+        assert_eq!(
+            Data::Bool(false),
+            eval_str(&mut runtime, "(str-eq-ci \"Foo\" \"bar\")")?
+        );
+        assert!(eval_str(&mut runtime, "(upper 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_string_predicates() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Str("hi".into()),
+            eval_str(&mut runtime, "(trim \"  hi \n \")")?
+        );
+        for (src, expected) in [
+            ("(starts-with? \"hello\" \"he\")", true),
+            ("(starts-with? \"hello\" \"lo\")", false),
+            ("(starts-with? \"hello\" \"\")", true),
+            ("(ends-with? \"hello\" \"lo\")", true),
+            ("(ends-with? \"hello\" \"he\")", false),
+            ("(ends-with? \"\" \"\")", true),
+        ] {
+            assert_eq!(Data::Bool(expected), eval_str(&mut runtime, src)?, "{src}");
+        }
+        assert!(eval_str(&mut runtime, "(trim 1)").is_err());
+        assert!(eval_str(&mut runtime, "(ends-with? \"1\" 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_replace() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Str("a_b_c".into()),
+            eval_str(&mut runtime, "(replace \"a-b-c\" \"-\" \"_\")")?
+        );
+        assert_eq!(
+            Data::Str("xa".into()),
+            eval_str(&mut runtime, "(replace \"aaa\" \"aa\" \"x\")")?
+        );
+        assert_eq!(
+            Data::Str("abc".into()),
+            eval_str(&mut runtime, "(replace \"abc\" \"-\" \"_\")")?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(replace \"abc\" \"\" \"_\")"),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_function_identity() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let f (fn (x) x))")?;
+        eval_str(&mut runtime, "(let g f)")?;
+        eval_str(&mut runtime, "(let h (fn (x) x))")?;
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(= f f)")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(= f g)")?);
+        assert_eq!(Data::Bool(false), eval_str(&mut runtime, "(= f h)")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(= + +)")?);
+        assert_eq!(Data::Empty, Data::Empty);
+        assert_ne!(Data::Empty, Data::Int(0));
+        Ok(())
+    }
+
+    #[test]
+    fn test_if_let() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(6),
+            eval_str(&mut runtime, "(if-let (x (+ 1 2)) (* x 2) 0)")?
+        );
+        assert!(runtime.ctx.stack.lookup("x").is_err());
+        assert_eq!(
+            Data::Str("none".into()),
+            eval_str(&mut runtime, "(if-let (x nil) x \"none\")")?
+        );
+        assert!(runtime.ctx.stack.lookup("x").is_err());
+        assert!(eval_str(&mut runtime, "(if-let (x 1) (+ x \"a\") 0)").is_err());
+        assert!(runtime.ctx.stack.lookup("x").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_loop_recur() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        // The plain sum of 0..100000 doesn't fit an i32, so sum the last digits.
+        assert_eq!(
+            Data::Int(450000),
+            eval_str(
+                &mut runtime,
+                "(loop (i 0 acc 0) (if (< i 100000) (recur (+ i 1) (+ acc (mod i 10))) acc))"
+            )?
+        );
+        assert!(runtime.ctx.stack.lookup("i").is_err());
+        assert!(matches!(
+            eval_str(&mut runtime, "(loop (i 0) (recur 1 2))"),
+            Err(LispError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            eval_str(&mut runtime, "(recur 1)"),
+            Err(LispError::Recur(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_misplaced_recur() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        match eval_str(
+            &mut runtime,
+            "(loop (i 0) (if (< i 3) (+ 100 (recur (+ i 1))) i))",
+        ) {
+            Err(LispError::SyntaxError(msg)) => {
+                assert_eq!("(recur (+ i 1)) is not the last thing its loop does.", msg)
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+        assert!(matches!(
+            eval_str(&mut runtime, "(loop (i 0) (do (recur 1) 2))"),
+            Err(LispError::SyntaxError(_))
+        ));
+        assert!(matches!(
+            eval_str(&mut runtime, "(loop (i 0) (do (let x (recur 1)) x))"),
+            Err(LispError::SyntaxError(_))
+        ));
+
+        eval_str(&mut runtime, "(let f (fn (x) (recur 9)))")?;
+        match eval_str(&mut runtime, "(loop (i 0) (if (< i 3) (f i) i))") {
+            Err(LispError::SyntaxError(msg)) => {
+                assert_eq!("recur can't reach a loop outside its function.", msg)
+            }
+            r => panic!("unexpected {:?}", r),
+        }
+
+        // Tail positions through if, do, let bodies, match and functions
+        // with loops of their own still work.
+        eval_str(
+            &mut runtime,
+            "(let count-to (fn (n) (loop (i 0) (if (< i n) (recur (+ i 1)) i))))",
+        )?;
+        let src = "(loop (i 0 acc 0)
+            (if (< i 3)
+              (do (let next (+ i 1))
+                  (match next
+                    (3 (recur next (+ acc (count-to 10))))
+                    (_ (when-let (n next) (recur n (+ acc 1))))))
+              acc))";
+        assert_eq!(Data::Int(12), eval_str(&mut runtime, src)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_destructuring_let() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let pair-fn (fn (x) (list x (* x 10))))")?;
+        assert_eq!(
+            Data::Int(22),
+            eval_str(&mut runtime, "(do (let (a b) (pair-fn 2)) (+ a b))")?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(let (a b c) (pair-fn 2))"),
+            Err(LispError::Runtime(_))
+        ));
+        assert!(eval_str(&mut runtime, "(let (a b) 5)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_syntax_quote() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let (_, literal) = crate::parser::node(b"(a (unquote (+ 1 2)))").unwrap();
+        assert_eq!(
+            Data::Quote(literal),
+            eval_str(&mut runtime, "(quote (a (unquote (+ 1 2))))")?
+        );
+        let (_, filled) = crate::parser::node(b"(a 3)").unwrap();
+        assert_eq!(
+            Data::Quote(filled),
+            eval_str(&mut runtime, "(syntax-quote (a (unquote (+ 1 2))))")?
+        );
+        eval_str(&mut runtime, "(let x '(* 2 3))")?;
+        assert_eq!(
+            Data::Int(7),
+            eval_str(
+                &mut runtime,
+                "(unquote (syntax-quote (+ (unquote x) (unquote (- 2 1)))))"
+            )?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_list_primitives() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let l (cons 1 (list 2 3)))")?;
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(car l)")?);
+        assert_eq!(
+            Data::list(vec![Data::Int(2), Data::Int(3)]),
+            eval_str(&mut runtime, "(cdr l)")?
+        );
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(len l)")?);
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(len \"hé\")")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(empty? (list))")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(car (list))"),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_prelude() -> Result<()> {
+        let mut runtime = Runtime::with_prelude()?;
+        assert_eq!(Data::Int(120), eval_str(&mut runtime, "(fact 5)")?);
+        assert_eq!(Data::Int(55), eval_str(&mut runtime, "(fib 10)")?);
+        assert!(eval_str(&mut runtime, "(fact 13)").is_err());
+        assert_eq!(
+            Data::list(vec![Data::Int(2), Data::Int(3)]),
+            eval_str(&mut runtime, "(filter (fn (x) (> x 1)) (range 0 4))")?
+        );
+        assert_eq!(
+            Data::Int(10_000),
+            eval_str(&mut runtime, "(len (filter even? (range 0 20000)))")?
+        );
+        assert_eq!(
+            Data::list(vec![Data::Int(19_999)]),
+            eval_str(
+                &mut runtime,
+                "(filter (fn (x) (> x 19998)) (range 0 20000))"
+            )?
+        );
+        assert!(Runtime::try_new()?.ctx.stack.lookup("fact").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_comment() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(comment (boom))")?);
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(comment)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_letrec() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let src = "(letrec (ev? (fn (n) (if (= n 0) true (od? (- n 1))))
+                            od? (fn (n) (if (= n 0) false (ev? (- n 1)))))
+                     (list (ev? 10) (od? 7) (ev? 3)))";
+        assert_eq!(
+            Data::list(vec![Data::Bool(true), Data::Bool(true), Data::Bool(false)]),
+            eval_str(&mut runtime, src)?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(ev? 2)"),
+            Err(LispError::VariableNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_to_radix() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Str("1010".into()),
+            eval_str(&mut runtime, "(to-radix 10 2)")?
+        );
+        assert_eq!(
+            Data::Str("ff".into()),
+            eval_str(&mut runtime, "(to-radix 255 16)")?
+        );
+        assert_eq!(
+            Data::Str("-ff".into()),
+            eval_str(&mut runtime, "(to-radix (- 0 255) 16)")?
+        );
+        assert_eq!(
+            Data::Str("0".into()),
+            eval_str(&mut runtime, "(to-radix 0 36)")?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(to-radix 10 37)"),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_ok_err() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let good (ok 5) bad (err \"nope\"))")?;
+        assert_eq!(
+            Data::Ok(Box::new(Data::Int(5))),
+            eval_str(&mut runtime, "(ok 5)")?
+        );
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(ok? good)")?);
+        assert_eq!(Data::Bool(false), eval_str(&mut runtime, "(err? good)")?);
+        assert_eq!(Data::Bool(false), eval_str(&mut runtime, "(ok? bad)")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(err? bad)")?);
+        assert_eq!(Data::Int(5), eval_str(&mut runtime, "(unwrap good)")?);
+        match eval_str(&mut runtime, "(unwrap bad)") {
+            Err(LispError::Runtime(msg)) => assert!(msg.contains("nope")),
+            r => panic!("unexpected {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_do_scope() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let src = "(do (let twice (fn (x) (* x 2))) (let y (twice 4)) (twice y))";
+        assert_eq!(Data::Int(16), eval_str(&mut runtime, src)?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(twice 1)"),
+            Err(LispError::VariableNotFound(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_conversions() -> Result<()> {
+        assert_eq!(Data::Int(7), Data::from(7));
+        assert_eq!(7, i32::try_from(Data::from(7))?);
+        assert_eq!(Data::Str("hi".into()), Data::from("hi"));
+        assert_eq!("hi", String::try_from(Data::from(String::from("hi")))?);
+        assert_eq!(Data::Bool(true), true.into());
+        assert!(i32::try_from(Data::from("7")).is_err());
+        assert!(String::try_from(Data::from(7)).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_defmacro() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(
+            &mut runtime,
+            "(defmacro unless (c body) (syntax-quote (if (unquote c) 0 (unquote body))))",
+        )?;
+        assert_eq!(Data::Int(5), eval_str(&mut runtime, "(unless false 5)")?);
+        // The untaken branch is never evaluated.
+        assert_eq!(
+            Data::Int(0),
+            eval_str(&mut runtime, "(unless true (boom))")?
+        );
+
+        let (_, expanded) = crate::parser::node(b"(if (= 1 2) 0 (boom))").unwrap();
+        assert_eq!(
+            Data::Quote(expanded),
+            eval_str(&mut runtime, "(macroexpand (unless (= 1 2) (boom)))")?
+        );
+        let (_, plain) = crate::parser::node(b"(+ 1 2)").unwrap();
+        assert_eq!(
+            Data::Quote(plain),
+            eval_str(&mut runtime, "(macroexpand (+ 1 2))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_scope() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let x 1)")?;
+        assert_eq!(
+            Data::Int(5),
+            eval_str(&mut runtime, "(with-scope (let x 2 y 3) (+ x y))")?
+        );
+        assert_eq!(&Data::Int(1), runtime.ctx.stack.lookup("x")?);
+        assert!(runtime.ctx.stack.lookup("y").is_err());
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(with-scope)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_nan() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(nan? nan)")?);
+        assert_eq!(
+            Data::Bool(true),
+            eval_str(&mut runtime, "(nan? (/ 0.0 0.0))")?
+        );
+        assert_eq!(Data::Bool(false), eval_str(&mut runtime, "(nan? 1.0)")?);
+        assert_eq!(Data::Bool(false), eval_str(&mut runtime, "(nan? 1)")?);
+        assert_eq!(Data::Bool(false), eval_str(&mut runtime, "(= nan nan)")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(!= nan nan)")?);
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(= 1.0 1.0)")?);
+        for op in ["<", ">", "<=", ">="] {
+            let src = format!("(list ({op} nan 1) ({op} 1 nan) ({op} nan nan))");
+            assert_eq!(
+                Data::list(vec![Data::Bool(false); 3]),
+                eval_str(&mut runtime, &src)?
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_truthiness() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(car (list nil))")?);
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(if nil 1 2)")?);
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(if false 1 2)")?);
+        for value in ["0", "\"\"", "(list)", "'false", "'x", "(fn (x) x)"] {
+            let src = format!("(if {value} 1 2)");
+            assert_eq!(Data::Int(1), eval_str(&mut runtime, &src)?, "{value}");
+        }
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct SharedBuf(std::rc::Rc<std::cell::RefCell<Vec<u8>>>);
+
+    impl std::io::Write for SharedBuf {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_buffered_output() -> Result<()> {
+        let out = SharedBuf::default();
+        let mut runtime = Runtime::try_new()?;
+        runtime.set_output(out.clone())?;
+        eval_str(&mut runtime, "(debug 1 \"a\")")?;
+        assert!(out.0.borrow().is_empty());
+        eval_str(&mut runtime, "(flush)")?;
+        assert_eq!(b"Int(1)\nStr(\"a\")\n", out.0.borrow().as_slice());
+
+        eval_str(&mut runtime, "(debug 2)")?;
+        drop(runtime);
+        assert!(out.0.borrow().ends_with(b"Int(2)\n"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_sleep() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let start = std::time::Instant::now();
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(sleep 1)")?);
+        assert!(start.elapsed() >= std::time::Duration::from_millis(1));
+        assert!(matches!(
+            eval_str(&mut runtime, "(sleep (- 0 1))"),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_vars() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        runtime.set_env("NOM_LISP_TEST_VAR", "1");
+        assert_eq!(
+            Data::Str("1".into()),
+            eval_str(&mut runtime, "(getenv \"NOM_LISP_TEST_VAR\")")?
+        );
+        eval_str(&mut runtime, "(setenv \"NOM_LISP_TEST_VAR\" \"2\")")?;
+        assert_eq!(
+            Data::Str("2".into()),
+            eval_str(&mut runtime, "(getenv \"NOM_LISP_TEST_VAR\")")?
+        );
+        assert!(std::env::var("NOM_LISP_TEST_VAR").is_err());
+        assert_eq!(
+            Data::Empty,
+            eval_str(&mut Runtime::try_new()?, "(getenv \"NOM_LISP_TEST_VAR\")")?
+        );
+        assert_eq!(
+            Data::Empty,
+            eval_str(&mut runtime, "(getenv \"NOM_LISP_TEST_UNSET\")")?
+        );
+        assert!(eval_str(&mut runtime, "(setenv \"A=B\" \"1\")").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_random_seed() -> Result<()> {
+        let src = "(do (seed! 42) (list (random 100) (random 100) (random 100) (random 100)))";
+        let mut runtime = Runtime::try_new()?;
+        let first = eval_str(&mut runtime, src)?;
+        let second = eval_str(&mut Runtime::try_new()?, src)?;
+        assert_eq!(first, second);
+        let Data::List(values) = first else {
+            panic!("expected a list");
+        };
+        assert!(values
+            .iter()
+            .all(|v| matches!(v, Data::Int(i) if (0..100).contains(i))));
+        assert!(eval_str(&mut runtime, "(random 0)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_display() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            "'(do 2 3)",
+            eval_str(&mut runtime, "'(do 2 3)")?.to_string()
+        );
+        assert_eq!(
+            "(1 2.5 \"s\" 'x nil)",
+            eval_str(&mut runtime, "(list 1 2.5 \"s\" 'x nil)")?.to_string()
+        );
+        assert_eq!(
+            "<fn (a b)>",
+            eval_str(&mut runtime, "(fn (a b) a)")?.to_string()
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_ordinal_accessors() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let l (list 1 2 3))")?;
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(first l)")?);
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(second l)")?);
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(third l)")?);
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(last l)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(second (list 1))"),
+            Err(LispError::Runtime(_))
+        ));
+        assert!(matches!(
+            eval_str(&mut runtime, "(last (list))"),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_chars_implode() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::list(vec![Data::Str("h".into()), Data::Str("é".into())]),
+            eval_str(&mut runtime, "(chars \"hé\")")?
+        );
+        assert_eq!(
+            Data::Str("héllo".into()),
+            eval_str(&mut runtime, "(implode (chars \"héllo\"))")?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(implode (list \"a\" 1))"),
+            Err(LispError::TypeError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_step_limit() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        runtime.set_step_limit(Some(10_000));
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(+ 1 2)")?);
+        let endless = "(loop (i 0) (recur (+ i 1)))";
+        match eval_str(&mut runtime, endless) {
+            Err(LispError::Runtime(msg)) => assert_eq!("evaluation step limit exceeded", msg),
+            r => panic!("unexpected {:?}", r),
+        }
+        // Every top-level evaluation gets a fresh budget.
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(+ 1 2)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_sandboxed() -> Result<()> {
+        let mut runtime = Runtime::sandboxed()?;
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "(+ 1 2)")?);
+        for name in ["read-bytes", "getenv", "setenv", "sleep", "argv"] {
+            assert!(matches!(
+                runtime.ctx.stack.lookup(name),
+                Err(LispError::VariableNotFound(_))
+            ));
+        }
+        runtime.reset()?;
+        assert!(runtime.ctx.stack.lookup("read-bytes").is_err());
+        assert!(Runtime::try_new()?.ctx.stack.lookup("read-bytes").is_ok());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quote_list_conversion() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::list(vec![
+                Data::Quote(Node::sym("+")),
+                Data::Quote(Node::int(1)),
+                Data::Quote(Node::list(vec![Node::sym("f")])),
+            ]),
+            eval_str(&mut runtime, "(quote->list '(+ 1 (f)))")?
+        );
+        assert_eq!(
+            Data::Quote(Node::list(vec![Node::sym("+"), Node::int(1), Node::int(2)])),
+            eval_str(&mut runtime, "(list->quote (list '+ 1 2))")?
+        );
+        assert_eq!(
+            Data::Int(3),
+            eval_str(&mut runtime, "(eval (list->quote (list '+ 1 2)))")?
+        );
+        assert_eq!(
+            Data::Int(6),
+            eval_str(
+                &mut runtime,
+                "(eval (list->quote (cons '* (cdr (quote->list '(+ 2 3))))))"
+            )?
+        );
+        assert_eq!(Data::Int(4), eval_str(&mut runtime, "(eval 4)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_quoted_name_call() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, "('+ 1 2)")?);
+        eval_str(&mut runtime, "(let op '*)")?;
+        assert_eq!(Data::Int(6), eval_str(&mut runtime, "(op 2 3)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "('(+ 1) 2)"),
+            Err(LispError::TypeError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_defn_doc() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(
+            &mut runtime,
+            "(defn square \"Squares its argument\" (x) (* x x))",
+        )?;
+        assert_eq!(Data::Int(16), eval_str(&mut runtime, "(square 4)")?);
+        assert_eq!(
+            Data::Str("Squares its argument".into()),
+            eval_str(&mut runtime, "(doc square)")?
+        );
+        eval_str(
+            &mut runtime,
+            "(defn cube (x) (pre (> x 0)) (* x (square x)))",
+        )?;
+        assert_eq!(Data::Int(27), eval_str(&mut runtime, "(cube 3)")?);
+        assert!(eval_str(&mut runtime, "(cube 0)").is_err());
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(doc cube)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_match() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(
+            &mut runtime,
+            "(let describe (fn (x) (match x (1 \"one\") ((list a b) (+ a b)) ('sym \"symbol\") (_ \"other\"))))",
+        )?;
+        assert_eq!(
+            Data::Str("one".into()),
+            eval_str(&mut runtime, "(describe 1)")?
+        );
+        assert_eq!(
+            Data::Int(7),
+            eval_str(&mut runtime, "(describe (list 3 4))")?
+        );
+        assert_eq!(
+            Data::Str("symbol".into()),
+            eval_str(&mut runtime, "(describe 'sym)")?
+        );
+        assert_eq!(
+            Data::Str("other".into()),
+            eval_str(&mut runtime, "(describe (list 1 2 3))")?
+        );
+        assert_eq!(
+            Data::Str("other".into()),
+            eval_str(&mut runtime, "(describe 2)")?
+        );
+        assert!(runtime.ctx.stack.lookup("a").is_err());
+
+        assert_eq!(
+            Data::list(vec![Data::Int(2), Data::Int(3)]),
+            eval_str(
+                &mut runtime,
+                "(match (list 1 (list 2 3)) ((list 1 (list x y)) (list x y)))"
+            )?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(match 5 (4 0))"),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_deep_copy() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let b (str-builder \"a\"))")?;
+        eval_str(&mut runtime, "(let shallow b deep (deep-copy b))")?;
+        eval_str(&mut runtime, "(append! shallow \"b\")")?;
+        eval_str(&mut runtime, "(append! deep \"c\")")?;
+        assert_eq!(
+            Data::Str("ab".into()),
+            eval_str(&mut runtime, "(builder->str b)")?
+        );
+        assert_eq!(
+            Data::Str("ac".into()),
+            eval_str(&mut runtime, "(builder->str deep)")?
+        );
+
+        eval_str(&mut runtime, "(let l (list b) copied (deep-copy l))")?;
+        eval_str(&mut runtime, "(append! (car l) \"!\")")?;
+        assert_eq!(
+            Data::Str("ab!".into()),
+            eval_str(&mut runtime, "(builder->str b)")?
+        );
+        assert_eq!(
+            Data::Str("ab".into()),
+            eval_str(&mut runtime, "(builder->str (car copied))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_sum_product_count_if() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Int(6), eval_str(&mut runtime, "(sum (list 1 2 3))")?);
+        assert_eq!(
+            Data::Float(3.5),
+            eval_str(&mut runtime, "(sum (list 1 2.5))")?
+        );
+        assert_eq!(Data::Int(0), eval_str(&mut runtime, "(sum (list))")?);
+        assert_eq!(
+            Data::Int(24),
+            eval_str(&mut runtime, "(product (list 2 3 4))")?
+        );
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(product (list))")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(sum (list 1 \"a\"))"),
+            Err(LispError::TypeError(_))
+        ));
+        assert_eq!(
+            Data::Int(2),
+            eval_str(&mut runtime, "(count-if (fn (x) (> x 1)) (list 1 2 3))")?
+        );
+        assert_eq!(
+            Data::Int(0),
+            eval_str(&mut runtime, "(count-if (fn (x) true) (list))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_exit() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert!(matches!(
+            runtime.eval_program_str("(let x 1) (if x (exit 2) 0) (boom)"),
+            Err(LispError::Exit(2))
+        ));
+        assert!(matches!(
+            eval_str(&mut runtime, "(+ 1 (exit))"),
+            Err(LispError::Exit(0))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_bound() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(Data::Bool(true), eval_str(&mut runtime, "(is-bound? '+)")?);
+        assert_eq!(
+            Data::Bool(false),
+            eval_str(&mut runtime, "(is-bound? 'nope)")?
+        );
+        eval_str(&mut runtime, "(let nope 1)")?;
+        assert_eq!(
+            Data::Bool(true),
+            eval_str(&mut runtime, "(is-bound? \"nope\")")?
+        );
+        assert!(eval_str(&mut runtime, "(is-bound? 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_statistics() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Float(2.5),
+            eval_str(&mut runtime, "(mean (list 1 2 3 4))")?
+        );
+        assert_eq!(
+            Data::Float(2.0),
+            eval_str(&mut runtime, "(median (list 3 1 2))")?
+        );
+        assert_eq!(
+            Data::Float(2.5),
+            eval_str(&mut runtime, "(median (list 4 1 3 2))")?
+        );
+        assert_eq!(
+            Data::Float(2.0),
+            eval_str(&mut runtime, "(stddev (list 2 4 4 4 5 5 7 9))")?
+        );
+        for name in ["mean", "median", "stddev"] {
+            assert!(matches!(
+                eval_str(&mut runtime, &format!("({name} (list))")),
+                Err(LispError::Runtime(_))
+            ));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_max_collection_size() -> Result<()> {
+        let mut runtime = Runtime::with_prelude()?;
+        assert_eq!(
+            Data::list(vec![Data::Int(7); 3]),
+            eval_str(&mut runtime, "(repeat 3 7)")?
+        );
+        // Made before the cap, so only lists built from it are too big.
+        eval_str(&mut runtime, "(let big (repeat 150 0))")?;
+        runtime.set_max_collection_size(Some(100));
+        match eval_str(&mut runtime, "(len (repeat 100 0))")? {
+            Data::Int(100) => {}
+            r => panic!("unexpected {:?}", r),
+        }
+        eval_str(&mut runtime, "(let b (str-builder))")?;
+        eval_str(&mut runtime, "(append! b (implode (repeat 60 \"a\")))")?;
+        for src in [
+            "(repeat 101 0)",
+            "(range 0 101)",
+            "(implode (repeat 60 \"ab\"))",
+            "(replace (implode (repeat 60 \"a\")) \"a\" \"aa\")",
+            "(to-json (repeat 60 0))",
+            "(format \"{}{}\" (repeat 60 0) (repeat 60 0))",
+            "(map inc big)",
+            "(append! b (implode (repeat 60 \"a\")))",
+        ] {
+            match eval_str(&mut runtime, src) {
+                Err(LispError::Runtime(msg)) => assert_eq!("collection size limit exceeded", msg),
+                r => panic!("unexpected {:?} for {src}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_accessors_on_quoted_lists() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        // Items of a quoted list come back quoted.
+        let car = eval_str(&mut runtime, "(car '(1 2 3))")?;
+        assert_eq!(Data::Quote(crate::node!(1)), car);
+        let cdr = eval_str(&mut runtime, "(cdr '(1 2 3))")?;
+        assert_eq!(Data::Quote(crate::node!((2 3))), cdr);
+        let nth = eval_str(&mut runtime, "(nth '(a (b c)) 1)")?;
+        assert_eq!(Data::Quote(crate::node!((b c))), nth);
+        let walked = eval_str(&mut runtime, "(car (cdr (cdr '(1 2 3))))")?;
+        assert_eq!(Data::Quote(crate::node!(3)), walked);
+        let second = eval_str(&mut runtime, "(second '(x y))")?;
+        assert_eq!(Data::Quote(crate::node!(y)), second);
+        let last = eval_str(&mut runtime, "(last '(x y))")?;
+        assert_eq!(Data::Quote(crate::node!(y)), last);
+        // Runtime lists behave as before.
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(car (list 1 2 3))")?);
+        assert_eq!(
+            Data::Int(3),
+            eval_str(&mut runtime, "(nth (list 1 2 3) 2)")?
+        );
+        assert!(eval_str(&mut runtime, "(nth (list 1 2 3) 3)").is_err());
+        assert!(eval_str(&mut runtime, "(cdr (cdr '(1)))").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eval_all() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let results = runtime.eval_all("(let x 1) (car (list)) (+ x 1)");
+        assert_eq!(3, results.len());
+        assert!(results[0].is_ok());
+        assert!(results[1].is_err());
+        assert_eq!(&Data::Int(2), results[2].as_ref().unwrap());
+        let unparsed = runtime.eval_all("(+ 1 2) (+ 1");
+        assert_eq!(1, unparsed.len());
+        assert!(unparsed[0].is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_assert() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let x 4)")?;
+        assert_eq!(Data::Empty, eval_str(&mut runtime, "(assert (< x 5))")?);
+        match eval_str(&mut runtime, "(assert (= x 5))") {
+            Err(e) => assert!(e.to_string().contains("assertion failed: (= x 5)")),
+            r => panic!("unexpected {:?}", r),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_flatten() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let ints = |v: &[i32]| Data::list(v.iter().copied().map(Data::Int).collect());
+        assert_eq!(
+            ints(&[1, 2, 3, 4, 5]),
+            eval_str(&mut runtime, "(flatten (list 1 (list 2 (list 3 4)) 5))")?
+        );
+        assert_eq!(
+            Data::list(vec![
+                Data::Int(1),
+                Data::Int(2),
+                ints(&[3, 4]),
+                Data::Int(5)
+            ]),
+            eval_str(&mut runtime, "(flatten-1 (list 1 (list 2 (list 3 4)) 5))")?
+        );
+        assert_eq!(
+            ints(&[]),
+            eval_str(&mut runtime, "(flatten (list (list) (list)))")?
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_pprint() -> Result<()> {
+        let out = SharedBuf::default();
+        let mut runtime = Runtime::try_new()?;
+        runtime.set_output(out.clone())?;
+        eval_str(
+            &mut runtime,
+            "(pprint (list 1 (list 2 3) (list 4 (list 5 6))))",
+        )?;
+        eval_str(&mut runtime, "(pprint (list (list 1) \"a\") 4)")?;
+        eval_str(&mut runtime, "(pprint (list 1 2))")?;
+        runtime.flush()?;
+        let expected = "(1\n  (2 3)\n  (4\n    (5 6)))\n((1)\n    \"a\")\n(1 2)\n";
+        assert_eq!(expected, String::from_utf8(out.0.borrow().clone()).unwrap());
+        Ok(())
+    }
+
+    #[test]
+    fn test_fn_params_and_body() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::list(vec![
+                Data::Quote(Node::sym("a")),
+                Data::Quote(Node::sym("b"))
+            ]),
+            eval_str(&mut runtime, "(fn-params (fn (a b) (+ a b)))")?
+        );
+        assert_eq!(
+            Data::Quote(crate::node!((+ a b))),
+            eval_str(&mut runtime, "(fn-body (fn (a b) (+ a b)))")?
+        );
+        // The body can be evaluated again once the parameters are bound.
+        let src = "(do (let a 1) (let b 2) (eval (fn-body (fn (a b) (+ a b)))))";
+        assert_eq!(Data::Int(3), eval_str(&mut runtime, src)?);
+        assert!(eval_str(&mut runtime, "(fn-body 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_when_let() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let ran 0)")?;
+        let src = "(when-let (x (+ 1 2)) (let ran 1) (* x 2))";
+        assert_eq!(Data::Int(6), eval_str(&mut runtime, src)?);
+        // The body runs in its own scope.
+        assert_eq!(Data::Int(0), eval_str(&mut runtime, "(+ ran 0)")?);
+        assert!(eval_str(&mut runtime, "(+ x 0)").is_err());
+
+        let src = "(when-let (x nil) (car (list)))";
+        assert_eq!(Data::Empty, eval_str(&mut runtime, src)?);
+        assert!(eval_str(&mut runtime, "(when-let x 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bit_counts_and_parity() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            ("(popcount 255)", Data::Int(8)),
+            ("(popcount 0)", Data::Int(0)),
+            ("(popcount (- 0 1))", Data::Int(32)),
+            ("(leading-zeros 1)", Data::Int(31)),
+            ("(trailing-zeros 8)", Data::Int(3)),
+            ("(even? 4)", Data::Bool(true)),
+            ("(even? (- 0 3))", Data::Bool(false)),
+            ("(odd? 3)", Data::Bool(true)),
+            ("(odd? (- 0 3))", Data::Bool(true)),
+            ("(odd? 0)", Data::Bool(false)),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(expected, eval_str(&mut runtime, src)?, "{src}");
+        }
+        assert!(eval_str(&mut runtime, "(even? 1.0)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_time_ms() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let Data::Float(first) = eval_str(&mut runtime, "(time-ms)")? else {
+            panic!("time-ms should return a float");
+        };
+        let Data::Float(second) = eval_str(&mut runtime, "(time-ms)")? else {
+            panic!("time-ms should return a float");
+        };
+        assert!(first <= second);
+        assert_eq!(first, first.trunc());
+        let wall_clock = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_millis() as f64;
+        assert!(
+            (wall_clock - second).abs() < 60_000.0,
+            "{second} vs {wall_clock}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            (r#"(regex-match "\d+" "abc123def")"#, Data::from("123")),
+            (
+                r#"(regex-match "^a(b|c)*d?$" "abcbd")"#,
+                Data::from("abcbd"),
+            ),
+            (
+                r#"(regex-match "[A-Z][a-z]{2,3}" "say Hello")"#,
+                Data::from("Hell"),
+            ),
+            (r#"(regex-match "x+" "abc")"#, Data::Empty),
+            (r#"(regex-match "^b" "abc")"#, Data::Empty),
+            (
+                r#"(regex-replace "\s+" " " "a  b   c")"#,
+                Data::from("a b c"),
+            ),
+            (
+                r#"(regex-replace "[aeiou]" "" "regular")"#,
+                Data::from("rglr"),
+            ),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(expected, eval_str(&mut runtime, src)?, "{src}");
+        }
+        for pattern in ["(ab", "[a-", "*a", "a{3,1}", "\\q"] {
+            let src = format!("(regex-match \"{pattern}\" \"a\")");
+            match eval_str(&mut runtime, &src) {
+                Err(LispError::Runtime(msg)) => assert!(msg.starts_with("Invalid regex"), "{msg}"),
+                r => panic!("unexpected {:?} for {pattern}", r),
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_fold_right() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let ints = |v: &[i32]| Data::list(v.iter().copied().map(Data::Int).collect());
+        let rebuilt = eval_str(&mut runtime, "(fold-right cons (list) (list 1 2 3))")?;
+        assert_eq!(ints(&[1, 2, 3]), rebuilt);
+        // 1 - (2 - (3 - 0)) against ((0 - 1) - 2) - 3.
+        let src = "(fold-right (fn (a b) (- a b)) 0 (list 1 2 3))";
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, src)?);
+        let src = "(reduce (fn (a b) (- a b)) 0 (list 1 2 3))";
+        assert_eq!(Data::Int(-6), eval_str(&mut runtime, src)?);
+        let src = "(fold-right cons 0 (list))";
+        assert_eq!(Data::Int(0), eval_str(&mut runtime, src)?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cons_shares_tail() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        eval_str(&mut runtime, "(let xs (repeat 3 0))")?;
+        eval_str(&mut runtime, "(let ys (cons 1 xs))")?;
+        eval_str(&mut runtime, "(let zs (cdr ys))")?;
+        let lookup = |runtime: &Runtime, name: &str| -> List {
+            let (_, data) = runtime
+                .bindings()
+                .into_iter()
+                .find(|(n, _)| *n == name)
+                .unwrap();
+            coerce::expect_list(data, name).unwrap().clone()
+        };
+        let xs = lookup(&runtime, "xs");
+        assert!(List::ptr_eq(&xs, &lookup(&runtime, "ys").rest().unwrap()));
+        assert!(List::ptr_eq(&xs, &lookup(&runtime, "zs")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_special_forms_cant_be_rebound() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        for src in [
+            "(let if 5)",
+            "(let (x fn) (list 1 2))",
+            "(defn let (x) x)",
+            "(fn (quote) 1)",
+            "(loop (recur 0) 1)",
+            "(if-let (do 1) 1 2)",
+            "(match 5 (if (debug if)))",
+            "(match (list 1 2) ((list a loop) a))",
+        ] {
+            match eval_str(&mut runtime, src) {
+                Err(LispError::SyntaxError(msg)) => {
+                    assert!(msg.starts_with("cannot rebind special form"), "{msg}")
+                }
+                r => panic!("unexpected {:?} for {src}", r),
+            }
+        }
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(if true 1 2)")?);
+        // Ordinary intrinsics can still be shadowed.
+        eval_str(&mut runtime, "(let car 5)")?;
+        assert_eq!(Data::Int(6), eval_str(&mut runtime, "(+ car 1)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partial_application() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(7),
+            eval_str(&mut runtime, "(((fn (a b) (+ a b)) 3) 4)")?
+        );
+        eval_str(
+            &mut runtime,
+            "(defn add3 (a b c) (+ a (+ (* 10 b) (* 100 c))))",
+        )?;
+        eval_str(&mut runtime, "(let add-1 (add3 1))")?;
+        eval_str(&mut runtime, "(let add-1-2 (add-1 2))")?;
+        assert_eq!(Data::Int(321), eval_str(&mut runtime, "(add-1-2 3)")?);
+        assert_eq!(Data::Int(521), eval_str(&mut runtime, "(add-1 2 5)")?);
+        assert_eq!(
+            Data::list(vec![Data::Int(11), Data::Int(12)]),
+            eval_str(&mut runtime, "(map ((fn (a b) (+ a b)) 10) (list 1 2))")?
+        );
+        assert!(eval_str(&mut runtime, "(add-1-2 3 4)").is_err());
+        assert!(eval_str(&mut runtime, "(add3 1 2 3 4)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_compose() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(7),
+            eval_str(&mut runtime, "((compose inc inc) 5)")?
+        );
+        // The rightmost function runs first.
+        let src = "((compose (fn (x) (* x 2)) inc (fn (x) (- x 10))) 15)";
+        assert_eq!(Data::Int(12), eval_str(&mut runtime, src)?);
+        let src = "((compose (compose inc inc) (compose dec)) 1)";
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, src)?);
+        assert!(eval_str(&mut runtime, "(compose inc 5)").is_err());
+        assert!(eval_str(&mut runtime, "(compose)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_letfn() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let src = "(letfn ((ev? (n) (if (= n 0) true (od? (- n 1))))
+                           (od? (n) (if (= n 0) false (ev? (- n 1)))))
+                     (list (ev? 10) (od? 7) (ev? 3)))";
+        assert_eq!(
+            Data::list(vec![Data::Bool(true), Data::Bool(true), Data::Bool(false)]),
+            eval_str(&mut runtime, src)?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(ev? 2)"),
+            Err(LispError::VariableNotFound(_))
+        ));
+        assert!(eval_str(&mut runtime, "(letfn ((f)) 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_integer_range_checks() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(-42),
+            eval_str(&mut runtime, "(str->int \" -42\")")?
+        );
+        assert_eq!(
+            Data::list(vec![Data::Int(12)]),
+            eval_str(&mut runtime, "(from-json \"[12]\")")?
+        );
+        for src in [
+            "(str->int \"3000000000\")",
+            "(str->int \"-3000000000\")",
+            "(from-json \"[1, 3000000000]\")",
+        ] {
+            match eval_str(&mut runtime, src) {
+                Err(LispError::Runtime(msg)) => assert_eq!("integer out of range", msg),
+                r => panic!("unexpected {:?} for {src}", r),
+            }
+        }
+        assert!(eval_str(&mut runtime, "(str->int \"12a\")").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_swap() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::Int(2),
+            eval_str(&mut runtime, "(do (let n 1) (swap! n inc) n)")?
+        );
+        // The binding is updated where it lives, not shadowed.
+        eval_str(&mut runtime, "(let total 10)")?;
+        eval_str(
+            &mut runtime,
+            "(do (swap! total + 5) (swap! total (fn (x) (* x 2))))",
+        )?;
+        assert_eq!(Data::Int(30), eval_str(&mut runtime, "(+ total 0)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(swap! missing inc)"),
+            Err(LispError::VariableNotFound(_))
+        ));
+        assert!(eval_str(&mut runtime, "(swap! if inc)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_boolean_predicates() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            ("(truthy? 1)", true),
+            ("(true? 1)", false),
+            ("(true? true)", true),
+            ("(false? false)", true),
+            ("(false? nil)", false),
+            ("(truthy? nil)", false),
+            ("(truthy? (list))", true),
+            ("(truthy? false)", false),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(Data::Bool(expected), eval_str(&mut runtime, src)?, "{src}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_remove_and_dissoc() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let ints = |v: &[i32]| Data::list(v.iter().copied().map(Data::Int).collect());
+        eval_str(&mut runtime, "(let xs (list 1 2 1 3))")?;
+        assert_eq!(ints(&[2, 3]), eval_str(&mut runtime, "(remove xs 1)")?);
+        assert_eq!(
+            ints(&[1, 2, 1, 3]),
+            eval_str(&mut runtime, "(remove xs 4)")?
+        );
+        // The original is left alone.
+        assert_eq!(ints(&[1, 2, 1, 3]), eval_str(&mut runtime, "(do xs)")?);
+
+        eval_str(&mut runtime, "(let m (list (list \"a\" 1) (list \"b\" 2)))")?;
+        let b_only = Data::list(vec![Data::list(vec![Data::from("b"), Data::Int(2)])]);
+        assert_eq!(b_only, eval_str(&mut runtime, "(dissoc m \"a\")")?);
+        let unchanged = eval_str(&mut runtime, "(dissoc m \"z\")")?;
+        assert_eq!(eval_str(&mut runtime, "(do m)")?, unchanged);
+        assert!(eval_str(&mut runtime, "(dissoc (list 1 2) 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_of() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let mut size = |src: &str| match eval_str(&mut runtime, src) {
+            Ok(Data::Int(n)) => n,
+            r => panic!("unexpected {:?} for {src}", r),
+        };
+        let short = size("(size-of \"ab\")");
+        let long = size("(size-of \"a much longer string\")");
+        assert!(short < long);
+        assert!(size("(size-of 1)") < short);
+        assert!(size("(size-of (list 1 2))") < size("(size-of (list 1 2 3))"));
+        assert!(size("(size-of (list \"ab\"))") < size("(size-of (list \"abcdef\"))"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_type_of() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            ("(type-of 1)", "int"),
+            ("(type-of 1.5)", "float"),
+            ("(type-of \"s\")", "string"),
+            ("(type-of (list 1 2))", "list"),
+            ("(type-of (fn (x) x))", "function"),
+            ("(type-of +)", "intrinsic"),
+            ("(type-of (quote x))", "quote"),
+            ("(type-of (do nil))", "nil"),
+            ("(type-of (= 1 1))", "bool"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(Data::from(expected), eval_str(&mut runtime, src)?, "{src}");
+        }
+        assert!(matches!(
+            eval_str(&mut runtime, "(type-of 1 2)"),
+            Err(LispError::SyntaxError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_user_bindings() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert!(runtime.user_bindings().is_empty());
+        eval_str(&mut runtime, "(let x 1)")?;
+        eval_str(&mut runtime, "(let + -)")?;
+        let names: Vec<_> = runtime.user_bindings().iter().map(|(n, _)| *n).collect();
+        assert_eq!(vec!["x"], names);
+        runtime.reset()?;
+        assert!(runtime.user_bindings().is_empty());
+
+        let mut runtime = Runtime::with_prelude()?;
+        assert!(runtime.user_bindings().is_empty());
+        eval_str(&mut runtime, "(let y 1)")?;
+        eval_str(&mut runtime, "(defn filter (f xs) xs)")?;
+        let names: Vec<_> = runtime.user_bindings().iter().map(|(n, _)| *n).collect();
+        assert_eq!(vec!["y"], names);
+        Ok(())
+    }
+
+    #[test]
+    fn test_format() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::from("a b a"),
+            eval_str(&mut runtime, r#"(format "{0} {1} {0}" "a" "b")"#)?
+        );
+        assert_eq!(
+            Data::from("(2 3)-1"),
+            eval_str(&mut runtime, r#"(format "{1}-{0}" 1 (list 2 3))"#)?
+        );
+        assert_eq!(
+            Data::from("1 + 2 = 3"),
+            eval_str(&mut runtime, r#"(format "{} + {} = {}" 1 2 (+ 1 2))"#)?
+        );
+        assert_eq!(
+            Data::from("{x}"),
+            eval_str(&mut runtime, r#"(format "{{{}}}" "x")"#)?
+        );
+        assert_eq!(
+            Data::from("plain"),
+            eval_str(&mut runtime, r#"(format "plain")"#)?
+        );
+        for src in [
+            r#"(format "{0} {}" "a" "b")"#,
+            r#"(format "{} {1}" "a" "b")"#,
+            r#"(format "{2}" "a")"#,
+            r#"(format "{}")"#,
+            r#"(format "{x}" 1)"#,
+            r#"(format "{0" 1)"#,
+            r#"(format "}" 1)"#,
+        ] {
+            assert!(
+                matches!(eval_str(&mut runtime, src), Err(LispError::Runtime(_))),
+                "{src}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_try_catch() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let handlers = "(type-error \"type\") (runtime-error \"runtime\")";
+        assert_eq!(
+            Data::from("type"),
+            eval_str(&mut runtime, &format!("(try-catch (+ 1 \"x\") {handlers})"))?
+        );
+        assert_eq!(
+            Data::from("runtime"),
+            eval_str(&mut runtime, &format!("(try-catch (/ 1 0) {handlers})"))?
+        );
+        assert_eq!(
+            Data::from("other"),
+            eval_str(
+                &mut runtime,
+                &format!("(try-catch (undefined-name) {handlers} (_ \"other\"))")
+            )?
+        );
+        assert_eq!(
+            Data::from("Runtime error: assertion failed: (= 1 2)"),
+            eval_str(
+                &mut runtime,
+                "(try-catch (assert (= 1 2)) (runtime-error msg msg))"
+            )?
+        );
+        assert_eq!(
+            Data::Int(3),
+            eval_str(&mut runtime, "(try-catch (+ 1 2) (_ 0))")?
+        );
+        // Unmatched errors pass through unchanged.
+        assert!(matches!(
+            eval_str(&mut runtime, "(try-catch (+ 1 \"x\") (runtime-error 0))"),
+            Err(e) if e.kind() == Some("type-error")
+        ));
+        // Neither recur nor exit is an error to catch.
+        assert_eq!(
+            Data::Int(5),
+            eval_str(
+                &mut runtime,
+                "(loop (i 0) (if (< i 5) (try-catch (recur (+ i 1)) (_ 0)) i))"
+            )?
+        );
+        assert!(matches!(
+            eval_str(&mut runtime, "(try-catch (exit 2) (_ 0))"),
+            Err(LispError::Exit(2))
+        ));
+        // The failed body's scopes don't leak into what follows.
+        eval_str(
+            &mut runtime,
+            "(try-catch (with-scope (let y 1) (+ y \"x\")) (type-error 0))",
+        )?;
+        assert!(eval_str(&mut runtime, "(do y)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_distinct() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            ("(distinct (list 1 2 2 3 1))", "(1 2 3)"),
+            (
+                r#"(distinct (list "b" "a" "b" "c" "a"))"#,
+                r#"("b" "a" "c")"#,
+            ),
+            (
+                r#"(distinct (list 1 "1" 1.0 (list 1) (list 1) 1))"#,
+                r#"(1 "1" 1.0 (1))"#,
+            ),
+            ("(distinct (list 0.0 (- 0 0.0) nan nan))", "(0.0 NaN NaN)"),
+            (
+                "(distinct (list 'a 'b 'a (list 'a) (list 'a)))",
+                "('a 'b ('a))",
+            ),
+            ("(distinct (list))", "()"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(expected, eval_str(&mut runtime, src)?.to_string(), "{src}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_partition() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            "((2 4) (1 3))",
+            eval_str(&mut runtime, "(partition even? (list 1 2 3 4))")?.to_string()
+        );
+        assert_eq!(
+            "((5 4 3) (1 2))",
+            eval_str(
+                &mut runtime,
+                "(partition (fn (x) (> x 2)) (list 5 1 4 2 3))"
+            )?
+            .to_string()
+        );
+        assert_eq!(
+            "(() ())",
+            eval_str(&mut runtime, "(partition even? (list))")?.to_string()
+        );
+        assert!(eval_str(&mut runtime, "(partition even? 1)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_eq_and_equal() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        runtime.eval_program_str("(let a (list 1 (list 2 3)) b (list 1 (list 2 3)) c a)")?;
+        let cases = [
+            ("(equal? a b)", true),
+            ("(eq? a b)", false),
+            ("(eq? a a)", true),
+            ("(equal? a a)", true),
+            ("(eq? a c)", true),
+            // The tail is shared, not copied.
+            ("(eq? (cdr a) (cdr c))", true),
+            ("(eq? (cons 0 a) (cons 0 a))", false),
+            ("(eq? (list) (list))", true),
+            (r#"(eq? "s" "s")"#, true),
+            ("(eq? 1 1)", true),
+            ("(eq? 1 1.0)", false),
+            ("(eq? (fn (x) x) (fn (x) x))", false),
+            ("(eq? (ok a) (ok a))", true),
+            ("(eq? (ok a) (ok b))", false),
+            ("(equal? (ok a) (ok b))", true),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(Data::Bool(expected), eval_str(&mut runtime, src)?, "{src}");
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_with_redefs() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        runtime.eval_program_str("(defn add-one (x) (+ x 1)) (let n 10)")?;
+        assert_eq!(
+            Data::Int(4),
+            eval_str(&mut runtime, "(with-redefs (+ -) (+ 5 1))")?
+        );
+        // Functions called from the body see the stub too.
+        assert_eq!(
+            Data::Int(9),
+            eval_str(&mut runtime, "(with-redefs (+ - n 0) (add-one 10))")?
+        );
+        assert_eq!(Data::Int(6), eval_str(&mut runtime, "(+ 5 1)")?);
+        assert_eq!(Data::Int(10), eval_str(&mut runtime, "(do n)")?);
+        // Restored even when the body fails.
+        assert!(eval_str(&mut runtime, "(with-redefs (+ - fresh 1) (car 1))").is_err());
+        assert_eq!(Data::Int(6), eval_str(&mut runtime, "(+ 5 1)")?);
+        assert!(eval_str(&mut runtime, "(do fresh)").is_err());
+        assert_eq!(
+            Data::Int(2),
+            eval_str(&mut runtime, "(with-redefs (n 1 n 2) n)")?
+        );
+        assert_eq!(Data::Int(10), eval_str(&mut runtime, "(do n)")?);
+        assert!(matches!(
+            eval_str(&mut runtime, "(with-redefs (if 1) 0)"),
+            Err(LispError::SyntaxError(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_index_of_and_string_contains() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            (r#"(index-of "hello" "ll")"#, Data::Int(2)),
+            (r#"(index-of "hello" "l")"#, Data::Int(2)),
+            (r#"(index-of "hello" "")"#, Data::Int(0)),
+            (r#"(index-of "hello" "z")"#, Data::Int(-1)),
+            // "ü" and "ß" take two bytes each, so "x" is at byte 4.
+            (r#"(index-of "üßx" "x")"#, Data::Int(2)),
+            (r#"(string-contains "hello" "ell")"#, Data::Bool(true)),
+            (r#"(string-contains "hello" "elo")"#, Data::Bool(false)),
+            (r#"(string-contains "üßx" "ßx")"#, Data::Bool(true)),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(expected, eval_str(&mut runtime, src)?, "{src}");
+        }
+        assert!(eval_str(&mut runtime, r#"(index-of "hello" 1)"#).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_quote_deep() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        assert_eq!(
+            Data::list(vec![Data::Int(1), Data::Int(2), Data::Int(3)]),
+            eval_str(&mut runtime, "(quote-deep (1 2 3))")?
+        );
+        assert_eq!(
+            Data::list(vec![
+                Data::Str("a".into()),
+                Data::list(vec![Data::Float(1.5), Data::Quote(Node::sym("x"))]),
+            ]),
+            eval_str(&mut runtime, r#"(quote-deep ("a" (1.5 x)))"#)?
+        );
+        // Unlike quote, the result is an ordinary list.
+        assert_eq!(
+            Data::Int(6),
+            eval_str(&mut runtime, "(sum (quote-deep (1 2 3)))")?
+        );
+        assert!(eval_str(&mut runtime, "(sum (quote (1 2 3)))").is_err());
+        assert_eq!(
+            Data::Quote(Node::sym("x")),
+            eval_str(&mut runtime, "(quote-deep x)")?
+        );
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(quote-deep 1)")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_map_get_and_map_has() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        runtime.eval_program_str(r#"(let m (list (list "a" 1) (list "b" nil) (list "a" 2)))"#)?;
+        let cases = [
+            (r#"(map-get m "a")"#, Data::Int(1)),
+            (r#"(map-get m "a" 0)"#, Data::Int(1)),
+            (r#"(map-get m "z" 0)"#, Data::Int(0)),
+            (r#"(map-get m "z")"#, Data::Empty),
+            (r#"(map-get m "b" 0)"#, Data::Empty),
+            (r#"(map-has? m "b")"#, Data::Bool(true)),
+            (r#"(map-has? m "z")"#, Data::Bool(false)),
+            (r#"(map-has? (dissoc m "b") "b")"#, Data::Bool(false)),
+            (r#"(map-has? (list) "a")"#, Data::Bool(false)),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(expected, eval_str(&mut runtime, src)?, "{src}");
+        }
+        assert!(matches!(
+            eval_str(&mut runtime, r#"(map-get (list 1 2) "a")"#),
+            Err(LispError::TypeError(_))
+        ));
+        assert!(eval_str(&mut runtime, "(map-get m)").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_regex_long_and_pathological_inputs() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let long = "a".repeat(20_000);
+        assert_eq!(
+            Data::Str(long.clone()),
+            eval_str(&mut runtime, &format!(r#"(regex-match "a*" "{long}")"#))?
+        );
+        // Exponential for a backtracking matcher.
+        let a28 = "a".repeat(28);
+        assert_eq!(
+            Data::Empty,
+            eval_str(&mut runtime, &format!(r#"(regex-match "(a|a)*b" "{a28}")"#))?
+        );
+        // Leftmost, then the greedy or first-listed choice.
+        let cases = [
+            (r#"(regex-match "a|ab" "xab")"#, "a"),
+            (r#"(regex-match "x*" "b")"#, ""),
+            (r#"(regex-match "(a*)*b" "aab")"#, "aab"),
+            (r#"(regex-match "b+|a+" "aabb")"#, "aa"),
+            (r#"(regex-match "a{2,3}" "aaaa")"#, "aaa"),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(
+                Data::Str(expected.into()),
+                eval_str(&mut runtime, src)?,
+                "{src}"
+            );
+        }
+        assert!(matches!(
+            eval_str(&mut runtime, r#"(regex-match "(a{100}){200}" "a")"#),
+            Err(LispError::Runtime(_))
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_comments_are_not_evaluated() -> Result<()> {
+        let mut parser = crate::parser::Parser::new();
+        parser.set_keep_comments(true);
+        let cases = [
+            ("(+ 1 ; one\n 2)", "(+ 1 2)"),
+            ("(do (let x ; the answer\n 42) x)", "(do (let x 42) x)"),
+            ("(if ; check\n 1 2 3)", "(if 1 2 3)"),
+            ("(quote (a ; kept out\n b))", "(quote (a b))"),
+            ("'(a ; kept out\n b)", "'(a b)"),
+        ];
+        for (commented, plain) in cases {
+            let mut runtime = Runtime::try_new()?;
+            let (_, node) = parser.node(commented.as_bytes()).unwrap();
+            assert_eq!(
+                eval_str(&mut runtime, plain)?,
+                runtime.eval(node)?,
+                "{commented}"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_bool() -> Result<()> {
+        let mut runtime = Runtime::try_new()?;
+        let cases = [
+            ("(do true)", true),
+            ("(do false)", false),
+            ("(= 1 1)", true),
+            ("(!= 1 1)", false),
+            ("(>= 2 3)", false),
+            ("(str-eq-ci \"Ab\" \"aB\")", true),
+            ("(= true (= 2 2))", true),
+            ("(from-json \"false\")", false),
+        ];
+        for (src, expected) in cases {
+            assert_eq!(Data::Bool(expected), eval_str(&mut runtime, src)?, "{src}");
+        }
+        assert_eq!(
+            Data::Str("bool".into()),
+            eval_str(&mut runtime, "(type-of false)")?
+        );
+        assert_eq!(
+            Data::Str("true".into()),
+            eval_str(&mut runtime, "(to-json true)")?
+        );
+        // Only false and nil are falsy.
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(if false 1 2)")?);
+        assert_eq!(Data::Int(2), eval_str(&mut runtime, "(if (= 1 2) 1 2)")?);
+        assert_eq!(Data::Int(1), eval_str(&mut runtime, "(if 0 1 2)")?);
         Ok(())
     }
 }
@@ -0,0 +1,32 @@
+; Functions loaded by Runtime::with_prelude. Everything here is built from
+; the intrinsics, so it doubles as a small example of the language.
+
+(let not (fn (x) (if x false true)))
+
+(let abs (fn (n) (if (< n 0) (- 0 n) n)))
+
+(let min (fn (a b) (if (< b a) b a)))
+
+(let max (fn (a b) (if (< a b) b a)))
+
+; (range 0 3) is (0 1 2).
+(let range (fn (start end)
+  (loop (i (- end 1) acc (list))
+    (if (< i start) acc (recur (- i 1) (cons i acc))))))
+
+; Kept items are gathered back to front with recur, so long lists don't
+; grow the stack, then turned around.
+(let filter (fn (pred lst)
+  (loop (xs lst kept (list))
+    (if (empty? xs)
+      (loop (ys kept acc (list))
+        (if (empty? ys) acc (recur (cdr ys) (cons (car ys) acc))))
+      (recur (cdr xs) (if (pred (car xs)) (cons (car xs) kept) kept))))))
+
+; Arithmetic is checked by default, so these error out instead of wrapping
+; once the result no longer fits.
+(let fact (fn (n) (if (< n 2) 1 (* n (fact (- n 1))))))
+
+(let fib (fn (n)
+  (loop (a 0 b 1 i 0)
+    (if (< i n) (recur b (+ a b) (+ i 1)) a))))
@@ -0,0 +1,484 @@
+use super::error::{LispError, Result};
+
+/// A compiled regular expression for `regex-match` and `regex-replace`.
+///
+/// This is a small engine rather than a full one. It knows literals, `.`,
+/// `^`, `$`, classes like `[a-z]` and `[^0-9]`, the escapes
+/// `\d \w \s \D \W \S \n \t`, groups, `|`, and the greedy quantifiers
+/// `* + ?`, `{n}`, `{n,}` and `{n,m}`. Groups don't capture.
+///
+/// Patterns compile to a small program that is run over the text one char
+/// at a time, tracking every thread the pattern could be in at once, so
+/// matching takes time linear in the text and never recurses.
+pub struct Regex {
+    program: Vec<Inst>,
+}
+
+// Caps the program a pattern compiles to; counted repeats like a{1000}
+// are expanded into copies.
+const MAX_PROGRAM_LEN: usize = 10_000;
+
+enum Expr {
+    Literal(char),
+    Any,
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+    Start,
+    End,
+    Group(Vec<Vec<Expr>>),
+    Repeat {
+        expr: Box<Expr>,
+        min: usize,
+        max: Option<usize>,
+    },
+}
+
+// What a backslash escape stands for.
+enum Escape {
+    Char(char),
+    Class(ClassItem),
+}
+
+#[derive(Clone)]
+enum ClassItem {
+    Range(char, char),
+    Digit(bool),
+    Word(bool),
+    Space(bool),
+}
+
+impl ClassItem {
+    fn matches(&self, c: char) -> bool {
+        match *self {
+            ClassItem::Range(lo, hi) => (lo..=hi).contains(&c),
+            ClassItem::Digit(want) => c.is_ascii_digit() == want,
+            ClassItem::Word(want) => (c.is_alphanumeric() || c == '_') == want,
+            ClassItem::Space(want) => c.is_whitespace() == want,
+        }
+    }
+}
+
+enum Inst {
+    Literal(char),
+    Any,
+    Class {
+        negated: bool,
+        items: Vec<ClassItem>,
+    },
+    Start,
+    End,
+    /// Try both targets, preferring the first.
+    Split(usize, usize),
+    Jump(usize),
+    Match,
+}
+
+impl Regex {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let mut parser = PatternParser {
+            chars: pattern.chars().collect(),
+            pos: 0,
+        };
+        let program = parser
+            .alternation()
+            .and_then(|alternatives| match parser.peek() {
+                None => Ok(alternatives),
+                Some(_) => Err("unmatched )".to_owned()),
+            })
+            .and_then(|alternatives| {
+                let mut program = Vec::new();
+                compile_alternatives(&alternatives, &mut program)?;
+                program.push(Inst::Match);
+                Ok(program)
+            })
+            .map_err(|reason| {
+                LispError::Runtime(format!("Invalid regex {pattern:?}: {reason}."))
+            })?;
+        Ok(Regex { program })
+    }
+
+    /// The byte range of the leftmost match in `text`, if there is one.
+    pub fn find(&self, text: &str) -> Option<(usize, usize)> {
+        let chars: Vec<char> = text.chars().collect();
+        let (start, end) = self.find_at(&chars, 0)?;
+        Some((byte_offset(text, start), byte_offset(text, end)))
+    }
+
+    /// Replaces every non-overlapping match. The replacement is used as is;
+    /// there are no group references.
+    pub fn replace_all(&self, text: &str, replacement: &str) -> String {
+        let chars: Vec<char> = text.chars().collect();
+        let mut out = String::new();
+        let mut pos = 0;
+        while let Some((start, end)) = self.find_at(&chars, pos) {
+            out.extend(&chars[pos..start]);
+            out.push_str(replacement);
+            // An empty match would be found again at the same place.
+            if end == start {
+                out.extend(chars.get(start));
+                pos = start + 1;
+            } else {
+                pos = end;
+            }
+        }
+        out.extend(chars.get(pos..).unwrap_or_default());
+        out
+    }
+
+    // Works in char indices. Threads are kept in priority order, so the
+    // leftmost start wins and, among those, the greedy choice, just as a
+    // backtracking matcher would pick.
+    fn find_at(&self, text: &[char], from: usize) -> Option<(usize, usize)> {
+        let mut current = Threads::new(self.program.len());
+        let mut next = Threads::new(self.program.len());
+        let mut found = None;
+        for pos in from..=text.len() {
+            // A thread starting here ranks below every earlier one.
+            if found.is_none() {
+                self.add_thread(&mut current, 0, pos, pos, text.len());
+            }
+            if current.list.is_empty() {
+                break;
+            }
+            for i in 0..current.list.len() {
+                let (pc, start) = current.list[i];
+                let consumes = match &self.program[pc] {
+                    Inst::Match => {
+                        found = Some((start, pos));
+                        // Threads after this one rank lower.
+                        break;
+                    }
+                    Inst::Literal(l) => text.get(pos) == Some(l),
+                    Inst::Any => text.get(pos).is_some_and(|&c| c != '\n'),
+                    Inst::Class { negated, items } => text
+                        .get(pos)
+                        .is_some_and(|&c| items.iter().any(|item| item.matches(c)) != *negated),
+                    _ => false,
+                };
+                if consumes {
+                    self.add_thread(&mut next, pc + 1, start, pos + 1, text.len());
+                }
+            }
+            std::mem::swap(&mut current, &mut next);
+            next.clear();
+        }
+        found
+    }
+
+    // Follows jumps, splits and anchors from pc, adding every thread that
+    // waits on a char (or has matched) in priority order.
+    fn add_thread(&self, threads: &mut Threads, pc: usize, start: usize, pos: usize, len: usize) {
+        let mut stack = vec![pc];
+        while let Some(pc) = stack.pop() {
+            if !threads.visit(pc) {
+                continue;
+            }
+            match self.program[pc] {
+                Inst::Jump(to) => stack.push(to),
+                Inst::Split(first, second) => {
+                    stack.push(second);
+                    stack.push(first);
+                }
+                Inst::Start => {
+                    if pos == 0 {
+                        stack.push(pc + 1);
+                    }
+                }
+                Inst::End => {
+                    if pos == len {
+                        stack.push(pc + 1);
+                    }
+                }
+                _ => threads.list.push((pc, start)),
+            }
+        }
+    }
+}
+
+// The threads for one position: (pc, start of its match) pairs, plus which
+// pcs have been reached, so each is only followed once.
+struct Threads {
+    list: Vec<(usize, usize)>,
+    seen: Vec<bool>,
+}
+
+impl Threads {
+    fn new(len: usize) -> Self {
+        Threads {
+            list: Vec::new(),
+            seen: vec![false; len],
+        }
+    }
+
+    fn visit(&mut self, pc: usize) -> bool {
+        !std::mem::replace(&mut self.seen[pc], true)
+    }
+
+    fn clear(&mut self) {
+        self.list.clear();
+        self.seen.fill(false);
+    }
+}
+
+fn byte_offset(text: &str, char_index: usize) -> usize {
+    text.char_indices()
+        .nth(char_index)
+        .map_or(text.len(), |(i, _)| i)
+}
+
+type ParseResult<T> = std::result::Result<T, String>;
+
+fn compile_alternatives(alternatives: &[Vec<Expr>], program: &mut Vec<Inst>) -> ParseResult<()> {
+    // Each alternative but the last is Split(this, rest) ... Jump(end).
+    let mut jumps = Vec::new();
+    for (i, seq) in alternatives.iter().enumerate() {
+        let last = i + 1 == alternatives.len();
+        let split = program.len();
+        if !last {
+            program.push(Inst::Split(split + 1, 0));
+        }
+        for expr in seq {
+            compile(expr, program)?;
+        }
+        if !last {
+            jumps.push(program.len());
+            program.push(Inst::Jump(0));
+            let rest = program.len();
+            program[split] = Inst::Split(split + 1, rest);
+        }
+    }
+    let end = program.len();
+    for jump in jumps {
+        program[jump] = Inst::Jump(end);
+    }
+    Ok(())
+}
+
+fn compile(expr: &Expr, program: &mut Vec<Inst>) -> ParseResult<()> {
+    if program.len() > MAX_PROGRAM_LEN {
+        return Err("pattern is too large".into());
+    }
+    match expr {
+        Expr::Literal(c) => program.push(Inst::Literal(*c)),
+        Expr::Any => program.push(Inst::Any),
+        Expr::Class { negated, items } => program.push(Inst::Class {
+            negated: *negated,
+            items: items.clone(),
+        }),
+        Expr::Start => program.push(Inst::Start),
+        Expr::End => program.push(Inst::End),
+        Expr::Group(alternatives) => compile_alternatives(alternatives, program)?,
+        Expr::Repeat { expr, min, max } => {
+            for _ in 0..*min {
+                compile(expr, program)?;
+            }
+            match max {
+                // loop: Split(body, out) body Jump(loop)
+                None => {
+                    let split = program.len();
+                    program.push(Inst::Split(split + 1, 0));
+                    compile(expr, program)?;
+                    program.push(Inst::Jump(split));
+                    program[split] = Inst::Split(split + 1, program.len());
+                }
+                // Each optional copy may be skipped to the end.
+                Some(max) => {
+                    let mut splits = Vec::new();
+                    for _ in *min..*max {
+                        splits.push(program.len());
+                        program.push(Inst::Split(0, 0));
+                        compile(expr, program)?;
+                    }
+                    let end = program.len();
+                    for split in splits {
+                        program[split] = Inst::Split(split + 1, end);
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+struct PatternParser {
+    chars: Vec<char>,
+    pos: usize,
+}
+
+impl PatternParser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.peek();
+        self.pos += 1;
+        c
+    }
+
+    fn eat(&mut self, c: char) -> bool {
+        let found = self.peek() == Some(c);
+        if found {
+            self.pos += 1;
+        }
+        found
+    }
+
+    fn alternation(&mut self) -> ParseResult<Vec<Vec<Expr>>> {
+        let mut alternatives = vec![self.sequence()?];
+        while self.eat('|') {
+            alternatives.push(self.sequence()?);
+        }
+        Ok(alternatives)
+    }
+
+    fn sequence(&mut self) -> ParseResult<Vec<Expr>> {
+        let mut seq = Vec::new();
+        while !matches!(self.peek(), None | Some('|') | Some(')')) {
+            let atom = self.atom()?;
+            seq.push(self.quantified(atom)?);
+        }
+        Ok(seq)
+    }
+
+    fn atom(&mut self) -> ParseResult<Expr> {
+        match self.next() {
+            Some('(') => {
+                let alternatives = self.alternation()?;
+                if !self.eat(')') {
+                    return Err("unclosed (".into());
+                }
+                Ok(Expr::Group(alternatives))
+            }
+            Some('[') => self.class(),
+            Some('.') => Ok(Expr::Any),
+            Some('^') => Ok(Expr::Start),
+            Some('$') => Ok(Expr::End),
+            Some('\\') => self.escape().map(|escaped| match escaped {
+                Escape::Char(c) => Expr::Literal(c),
+                Escape::Class(item) => Expr::Class {
+                    negated: false,
+                    items: vec![item],
+                },
+            }),
+            Some(c @ ('*' | '+' | '?' | '{')) => Err(format!("nothing to repeat before {c}")),
+            Some(c) => Ok(Expr::Literal(c)),
+            None => Err("unexpected end".into()),
+        }
+    }
+
+    fn quantified(&mut self, atom: Expr) -> ParseResult<Expr> {
+        let (min, max) = match self.peek() {
+            Some('*') => (0, None),
+            Some('+') => (1, None),
+            Some('?') => (0, Some(1)),
+            Some('{') => {
+                self.pos += 1;
+                let min = self.number()?;
+                let max = if self.eat(',') {
+                    match self.peek() {
+                        Some('}') => None,
+                        _ => Some(self.number()?),
+                    }
+                } else {
+                    Some(min)
+                };
+                if self.peek() != Some('}') {
+                    return Err("unclosed {".into());
+                }
+                if max.is_some_and(|max| max < min) {
+                    return Err(format!(
+                        "repeat range {{{min},{}}} is backwards",
+                        max.unwrap()
+                    ));
+                }
+                (min, max)
+            }
+            _ => return Ok(atom),
+        };
+        self.pos += 1;
+        if matches!(atom, Expr::Start | Expr::End) {
+            return Err("nothing to repeat".into());
+        }
+        Ok(Expr::Repeat {
+            expr: Box::new(atom),
+            min,
+            max,
+        })
+    }
+
+    fn number(&mut self) -> ParseResult<usize> {
+        let start = self.pos;
+        while self.peek().is_some_and(|c| c.is_ascii_digit()) {
+            self.pos += 1;
+        }
+        let digits: String = self.chars[start..self.pos].iter().collect();
+        digits
+            .parse()
+            .map_err(|_| "expected a repeat count".to_owned())
+    }
+
+    fn class(&mut self) -> ParseResult<Expr> {
+        let negated = self.eat('^');
+        let mut items = Vec::new();
+        // A ] right at the start is a literal.
+        if self.eat(']') {
+            items.push(ClassItem::Range(']', ']'));
+        }
+        loop {
+            let lo = match self.next() {
+                Some(']') => break,
+                Some('\\') => match self.escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(item) => {
+                        items.push(item);
+                        continue;
+                    }
+                },
+                Some(c) => c,
+                None => return Err("unclosed [".into()),
+            };
+            let is_range = self.peek() == Some('-')
+                && !matches!(self.chars.get(self.pos + 1), None | Some(']'));
+            if !is_range {
+                items.push(ClassItem::Range(lo, lo));
+                continue;
+            }
+            self.pos += 1;
+            let hi = match self.next() {
+                Some('\\') => match self.escape()? {
+                    Escape::Char(c) => c,
+                    Escape::Class(_) => {
+                        return Err("a class range can't end in a class escape".into())
+                    }
+                },
+                Some(c) => c,
+                None => return Err("unclosed [".into()),
+            };
+            if hi < lo {
+                return Err(format!("class range {lo}-{hi} is backwards"));
+            }
+            items.push(ClassItem::Range(lo, hi));
+        }
+        Ok(Expr::Class { negated, items })
+    }
+
+    // Called after a backslash.
+    fn escape(&mut self) -> ParseResult<Escape> {
+        Ok(match self.next() {
+            Some('d') => Escape::Class(ClassItem::Digit(true)),
+            Some('D') => Escape::Class(ClassItem::Digit(false)),
+            Some('w') => Escape::Class(ClassItem::Word(true)),
+            Some('W') => Escape::Class(ClassItem::Word(false)),
+            Some('s') => Escape::Class(ClassItem::Space(true)),
+            Some('S') => Escape::Class(ClassItem::Space(false)),
+            Some('n') => Escape::Char('\n'),
+            Some('t') => Escape::Char('\t'),
+            Some(c) if !c.is_alphanumeric() => Escape::Char(c),
+            Some(c) => return Err(format!("unknown escape \\{c}")),
+            None => return Err("trailing backslash".into()),
+        })
+    }
+}
@@ -0,0 +1,50 @@
+use std::{path::PathBuf, process::Command};
+
+fn write_script(name: &str, src: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("nom_lisp_{}_{name}", std::process::id()));
+    std::fs::write(&path, src).unwrap();
+    path
+}
+
+#[test]
+fn test_script_reads_argv() {
+    let script = write_script(
+        "argv.lisp",
+        "; prints its argument count\n(debug (len (argv)))\n",
+    );
+    let output = Command::new(env!("CARGO_BIN_EXE_nom-lisp"))
+        .arg(&script)
+        .args(["a", "b"])
+        .output()
+        .unwrap();
+    std::fs::remove_file(&script).unwrap();
+    assert!(output.status.success());
+    assert_eq!("Int(2)\n", String::from_utf8(output.stdout).unwrap());
+}
+
+#[test]
+fn test_script_error_exits_nonzero() {
+    let script = write_script("error.lisp", "(debug 1)\n(+ 1 \"x\")\n(debug 2)\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_nom-lisp"))
+        .arg(&script)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&script).unwrap();
+    assert!(!output.status.success());
+    assert_eq!("Int(1)\n", String::from_utf8(output.stdout).unwrap());
+    assert!(String::from_utf8(output.stderr)
+        .unwrap()
+        .contains("is not a number"));
+}
+
+#[test]
+fn test_script_exit_code() {
+    let script = write_script("exit.lisp", "(debug 1)\n(exit 2)\n(debug 2)\n");
+    let output = Command::new(env!("CARGO_BIN_EXE_nom-lisp"))
+        .arg(&script)
+        .output()
+        .unwrap();
+    std::fs::remove_file(&script).unwrap();
+    assert_eq!(Some(2), output.status.code());
+    assert_eq!("Int(1)\n", String::from_utf8(output.stdout).unwrap());
+}